@@ -1,16 +1,12 @@
 use eyre::bail;
 use eyre::Result;
-use futures_util::pin_mut;
 use futures_util::StreamExt;
-use log::debug;
 use std::path::PathBuf;
-use which::which;
 use xcommand::StdioType;
-use xcommand::XChildHandle;
 use xcommand::XCommand;
 use xcommand::XStatus;
 
-const DIR: &'static str = env!("CARGO_MANIFEST_DIR");
+const DIR: &str = env!("CARGO_MANIFEST_DIR");
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
@@ -24,7 +20,7 @@ pub async fn main() -> Result<()> {
     };
 
     // Loop over stdout/err output from the child process
-    let mut streamer = child.streamer();
+    let mut streamer = child.streamer()?;
     let mut stream = streamer.stream();
     while let Some(item) = stream.next().await {
         let (message_type, message) = item?;
@@ -35,6 +31,12 @@ pub async fn main() -> Result<()> {
             StdioType::Stderr => {
                 println!("[stderr]{}", message);
             }
+            StdioType::SideChannel(fd) => {
+                println!("[fd {}]{}", fd, message);
+            }
+            StdioType::Terminal => {
+                println!("[tty]{}", message);
+            }
         }
     }
 
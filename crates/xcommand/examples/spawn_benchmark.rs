@@ -0,0 +1,57 @@
+use eyre::Result;
+use futures_util::StreamExt;
+use std::time::{Duration, Instant};
+use xcommand::SpawnBackend;
+use xcommand::XCommand;
+
+const SPAWNS: u32 = 200;
+
+/// Time how long it takes to spawn '/bin/true' with a backend, and separately how long until it
+/// has been reaped
+async fn bench(backend: SpawnBackend) -> Result<(Duration, Duration)> {
+    let command = XCommand::builder("/bin/true")?.backend(backend).build();
+    let mut spawning = Duration::ZERO;
+    let mut total = Duration::ZERO;
+    for _ in 0..SPAWNS {
+        let start = Instant::now();
+        let mut child = command.spawn()?;
+        spawning += start.elapsed();
+
+        let mut streamer = child.streamer()?;
+        let mut stream = streamer.stream();
+        while let Some(item) = stream.next().await {
+            item?;
+        }
+        child.status().await?;
+        total += start.elapsed();
+    }
+    Ok((spawning / SPAWNS, total / SPAWNS))
+}
+
+/// Compare the fork and posix_spawn backends as the parent grows.
+/// Parent sizes in MiB can be passed as arguments
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    let sizes: Vec<usize> = match std::env::args().skip(1).map(|s| s.parse()).collect() {
+        Ok(sizes) if !Vec::is_empty(&sizes) => sizes,
+        _ => vec![0, 256, 1024],
+    };
+
+    println!("parent RSS | backend     | spawn()   | spawn to reap");
+    for size in sizes {
+        // Touch every page so that it is actually resident and has to be mapped in the child
+        let ballast = vec![1u8; size * 1024 * 1024];
+        for (name, backend) in [
+            ("fork", SpawnBackend::Fork),
+            ("posix_spawn", SpawnBackend::PosixSpawn),
+        ] {
+            let (spawning, total) = bench(backend).await?;
+            println!(
+                "{:>6} MiB | {:<11} | {:>9.1?} | {:>9.1?}",
+                size, name, spawning, total
+            );
+        }
+        drop(ballast);
+    }
+    Ok(())
+}
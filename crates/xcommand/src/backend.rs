@@ -0,0 +1,104 @@
+use crate::exec_args::ExecArgs;
+use nix::errno::Errno;
+use nix::unistd::Pid;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::prelude::RawFd;
+
+/// How the child process is created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpawnBackend {
+    /// posix_spawn() when the command has no options that need fork(), fork() otherwise
+    #[default]
+    Auto,
+    /// Always fork() and set the child up by hand. Supports every option
+    Fork,
+    /// Always posix_spawn(), which glibc implements with clone(CLONE_VM | CLONE_VFORK) and so
+    /// doesn't have to copy the parent's page tables. Spawning fails if an option that needs
+    /// fork() was set
+    PosixSpawn,
+}
+
+/// posix_spawn_file_actions_t that is destroyed on drop
+struct FileActions(libc::posix_spawn_file_actions_t);
+
+impl FileActions {
+    fn new() -> Result<Self, Errno> {
+        let mut actions = MaybeUninit::uninit();
+        check(unsafe { libc::posix_spawn_file_actions_init(actions.as_mut_ptr()) })?;
+        Ok(Self(unsafe { actions.assume_init() }))
+    }
+
+    fn dup2(&mut self, fd: RawFd, target: RawFd) -> Result<(), Errno> {
+        check(unsafe { libc::posix_spawn_file_actions_adddup2(&mut self.0, fd, target) })
+    }
+}
+
+impl Drop for FileActions {
+    fn drop(&mut self) {
+        unsafe { libc::posix_spawn_file_actions_destroy(&mut self.0) };
+    }
+}
+
+/// posix_spawnattr_t that is destroyed on drop
+struct Attributes(libc::posix_spawnattr_t);
+
+impl Attributes {
+    fn new() -> Result<Self, Errno> {
+        let mut attr = MaybeUninit::uninit();
+        check(unsafe { libc::posix_spawnattr_init(attr.as_mut_ptr()) })?;
+        Ok(Self(unsafe { attr.assume_init() }))
+    }
+
+    fn set_flags(&mut self, flags: libc::c_short) -> Result<(), Errno> {
+        check(unsafe { libc::posix_spawnattr_setflags(&mut self.0, flags) })
+    }
+}
+
+impl Drop for Attributes {
+    fn drop(&mut self) {
+        unsafe { libc::posix_spawnattr_destroy(&mut self.0) };
+    }
+}
+
+/// The posix_spawn family returns the error number rather than setting errno
+fn check(res: libc::c_int) -> Result<(), Errno> {
+    match res {
+        0 => Ok(()),
+        errno => Err(Errno::from_raw(errno)),
+    }
+}
+
+/// Spawn a child in a new session with the given fds as its stdout and stderr, and 'fds' placed
+/// at their (child fd number, fd) positions.
+/// Exec failures are reported here too, as glibc waits for the child to exec
+pub(crate) fn posix_spawn(
+    exec_args: &ExecArgs,
+    stdout: RawFd,
+    stderr: RawFd,
+    fds: &[(RawFd, OwnedFd)],
+) -> Result<Pid, Errno> {
+    let mut actions = FileActions::new()?;
+    actions.dup2(stdout, libc::STDOUT_FILENO)?;
+    actions.dup2(stderr, libc::STDERR_FILENO)?;
+    for (child_fd, fd) in fds {
+        actions.dup2(fd.as_raw_fd(), *child_fd)?;
+    }
+
+    let mut attr = Attributes::new()?;
+    // The flag constants are c_int in some libc releases and c_short in others
+    attr.set_flags(libc::POSIX_SPAWN_SETSID as _)?;
+
+    let mut pid = 0;
+    check(unsafe {
+        libc::posix_spawn(
+            &mut pid,
+            exec_args.path(),
+            &actions.0,
+            &attr.0,
+            exec_args.argv(),
+            exec_args.envp(),
+        )
+    })?;
+    Ok(Pid::from_raw(pid))
+}
@@ -1,40 +1,33 @@
-use crate::child_handle::XChildHandle;
-use crate::command::XCommand;
+use crate::command::{PreExecHook, XCommand};
+use crate::drain::DrainPolicy;
 use crate::env_var::EnvVar;
-use async_stream::stream;
+use crate::parse;
+use crate::tee::{TeeOptions, TeeTarget};
+use crate::IoPriority;
+use crate::SpawnBackend;
 use eyre::bail;
 use eyre::Result;
-use log::debug;
-use log::error;
-use log::info;
-use nix::pty::openpty;
-use nix::pty::OpenptyResult;
+use nix::sched::CpuSet;
+use nix::sys::resource::Resource;
 use nix::sys::signal::Signal;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::dup2;
-use nix::unistd::execve;
-use nix::unistd::pipe;
-use nix::unistd::setsid;
-use nix::unistd::ForkResult;
-use nix::unistd::{close, fork, Pid};
+use nix::unistd::{getgrouplist, Gid, Uid, User};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::CString;
-use std::fs::File;
-use std::mem;
-use std::os::fd::AsRawFd;
+use std::ffi::OsStr;
+use std::io;
 use std::os::fd::OwnedFd;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::FromRawFd;
 use std::os::unix::prelude::RawFd;
-use std::path::Path;
-use std::pin::Pin;
-use tokio::io::AsyncBufReadExt;
-use tokio::io::BufReader;
-use tokio_fd::AsyncFd;
-use tokio_stream::wrappers::LinesStream;
-use tokio_stream::{Stream, StreamExt, StreamMap};
-use which::which;
+use std::path::{Path, PathBuf};
+
+fn inherited_env() -> Result<Vec<EnvVar>> {
+    let mut env = Vec::new();
+    for (key, value) in env::vars() {
+        env.push(EnvVar::from_str_pair(&key, &value)?);
+    }
+    Ok(env)
+}
 
 fn path_to_cstring(path: &Path) -> CString {
     let bytes = path.as_os_str().as_bytes();
@@ -42,9 +35,7 @@ fn path_to_cstring(path: &Path) -> CString {
 }
 
 pub struct XCommandBuilder {
-    command: CString,
-    args: Vec<CString>,
-    env: Vec<EnvVar>,
+    inner: XCommand,
 }
 
 impl XCommandBuilder {
@@ -56,33 +47,82 @@ impl XCommandBuilder {
     /// Inherit the parent process' env vars
     pub fn inherit_environment<P: AsRef<Path>>(command: P) -> Result<Self> {
         let path = command.as_ref();
-        let mut env = Vec::new();
-        for (key, value) in env::vars() {
-            env.push(EnvVar::from_str_pair(&key, &value)?);
-        }
         Ok(XCommandBuilder {
-            command: path_to_cstring(path),
-            args: Vec::new(),
-            env,
+            inner: XCommand::new(path_to_cstring(path), Vec::new(), inherited_env()?),
         })
     }
 
+    /// Split a command line into the command and its args, inheriting the parent's environment.
+    /// With 'expand', variables are expanded against that environment.
+    /// A leading 'env [-i] NAME=value...', as 'XCommand::to_shell_string()' writes, changes the
+    /// environment the way env(1) would. A command name without a '/' is looked up in PATH
+    pub(crate) fn from_command_line(line: &str, expand: bool) -> Result<Self> {
+        let mut env = inherited_env()?;
+        let words = parse::split(line, expand.then_some(&env[..]))?;
+        let words = apply_env_prefix(&mut env, &words)?;
+        let Some((command, args)) = words.split_first() else {
+            bail!("No command in '{}'", line);
+        };
+        let command = OsStr::from_bytes(command);
+        let path = if command.as_bytes().contains(&b'/') {
+            PathBuf::from(command)
+        } else {
+            match which::which(command) {
+                Ok(path) => path,
+                Err(e) => bail!(
+                    "Unable to find '{}' in PATH: {}",
+                    command.to_string_lossy(),
+                    e
+                ),
+            }
+        };
+
+        let mut builder = XCommandBuilder {
+            inner: XCommand::new(path_to_cstring(&path), Vec::new(), env),
+        };
+        for arg in args {
+            builder.push_arg(arg)?;
+        }
+        Ok(builder)
+    }
+
     /// Do not inherit the parent process' env vars
     pub fn clean_environment<P: AsRef<Path>>(command: P) -> Self {
         let path = command.as_ref();
         XCommandBuilder {
-            command: path_to_cstring(path),
-            args: Vec::new(),
-            env: Vec::new(),
+            inner: XCommand::new(path_to_cstring(path), Vec::new(), Vec::new()),
         }
     }
 
+    /// Add an argument that may not be valid UTF-8
+    fn push_arg(&mut self, arg: &[u8]) -> Result<()> {
+        let Ok(arg) = CString::new(arg) else {
+            bail!(
+                "Unable to create CString from '{}'",
+                String::from_utf8_lossy(arg)
+            );
+        };
+        self.inner.args.push(arg);
+        Ok(())
+    }
+
     /// Set an argument for the process
     pub fn arg(mut self, arg: &str) -> Result<Self> {
         let Ok(arg) = CString::new(arg) else {
             bail!("Unable to create CString from '{}'", arg);
         };
-        self.args.push(arg);
+        self.inner.args.push(arg);
+        Ok(self)
+    }
+
+    /// Set an argument that is masked wherever the command is shown: in Debug and Display
+    /// output, logs and errors, and in the child's output with 'XStreamer::redact_secrets()'
+    pub fn secret_arg(mut self, arg: &str) -> Result<Self> {
+        let Ok(cstr) = CString::new(arg) else {
+            bail!("Unable to create CString from a secret arg");
+        };
+        self.inner.secrets.add(arg);
+        self.inner.args.push(cstr);
         Ok(self)
     }
 
@@ -97,7 +137,16 @@ impl XCommandBuilder {
             cstr_args.push(arg);
         }
 
-        self.args = cstr_args;
+        self.inner.args = cstr_args;
+        Ok(self)
+    }
+
+    /// Append args split from a command line the way a shell would, expanding '$VAR' and
+    /// '${VAR:-default}' against the builder's environment as it is at this point
+    pub fn shell_args(mut self, line: &str) -> Result<Self> {
+        for arg in parse::split(line, Some(&self.inner.env))? {
+            self.push_arg(&arg)?;
+        }
         Ok(self)
     }
 
@@ -105,19 +154,374 @@ impl XCommandBuilder {
     /// Note that any prior set env vars are cleared
     pub fn env(mut self, vars: &HashMap<&str, &str>) -> Result<Self> {
         for (k, v) in vars {
-            self.env.push(EnvVar::from_str_pair(k, v)?)
+            self.inner.env.push(EnvVar::from_str_pair(k, v)?)
         }
         Ok(self)
     }
 
     /// Add an environment value
     pub fn var(mut self, key: &str, value: &str) -> Result<Self> {
-        self.env.push(EnvVar::from_str_pair(key, value)?);
+        self.inner.env.push(EnvVar::from_str_pair(key, value)?);
+        Ok(self)
+    }
+
+    /// Set an environment value whose value is masked like a 'secret_arg()', replacing any
+    /// existing value for the key
+    pub fn secret_var(mut self, key: &str, value: &str) -> Result<Self> {
+        if self.set_var(key, value).is_err() {
+            bail!(
+                "Unable to create CString from the secret value of '{}'",
+                key
+            );
+        }
+        self.inner.secrets.add(value);
+        Ok(self)
+    }
+
+    /// Kill the process with SIGKILL when its XChildHandle is dropped before the process was
+    /// reaped (default false)
+    pub fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.inner.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    /// Have the kernel send `signal` to the process when its parent dies (Linux only).
+    /// Note that "parent" is the thread that called 'spawn()'. Spawning from a thread that may
+    /// exit first (e.g. one of tokio's blocking pool threads) will deliver the signal early
+    #[cfg(target_os = "linux")]
+    pub fn parent_death_signal(mut self, signal: Signal) -> Self {
+        self.inner.parent_death_signal = Some(signal);
+        self
+    }
+
+    /// Limit a resource of the process with setrlimit(2). Use 'nix::sys::resource::RLIM_INFINITY'
+    /// for no limit. Use 'XStatus::exceeded_limit()' to tell whether a limit killed the process
+    pub fn rlimit(mut self, resource: Resource, soft: u64, hard: u64) -> Self {
+        self.inner.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Set the niceness of the process, from -20 (most favorable) to 19 (least favorable).
+    /// Lowering it below the parent's requires CAP_SYS_NICE
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.inner.nice = Some(nice);
+        self
+    }
+
+    /// Restrict the process to the given CPUs
+    pub fn cpu_affinity(mut self, cpus: &[usize]) -> Result<Self> {
+        let mut set = CpuSet::new();
+        for cpu in cpus {
+            if set.set(*cpu).is_err() {
+                bail!("CPU {} is out of range", cpu);
+            }
+        }
+        self.inner.cpu_affinity = Some(set);
         Ok(self)
     }
 
+    /// Set the I/O scheduling class and priority of the process
+    pub fn io_priority(mut self, priority: IoPriority) -> Result<Self> {
+        priority.validate()?;
+        self.inner.io_priority = Some(priority);
+        Ok(self)
+    }
+
+    /// Set how likely the OOM killer is to pick the process, from -1000 (never) to 1000.
+    /// Lowering it below the parent's requires CAP_SYS_RESOURCE
+    pub fn oom_score_adj(mut self, adj: i32) -> Result<Self> {
+        if !(-1000..=1000).contains(&adj) {
+            bail!("oom_score_adj must be between -1000 and 1000, got {}", adj);
+        }
+        self.inner.oom_score_adj = Some(adj);
+        Ok(self)
+    }
+
+    /// Run the process as the given user id.
+    /// When spawning as root, the supplementary groups are cleared unless set with 'groups()',
+    /// see also 'user()'
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.inner.uid = Some(Uid::from_raw(uid));
+        self
+    }
+
+    /// Run the process with the given group id. As with 'uid()', root's supplementary groups
+    /// are cleared unless set with 'groups()'
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.inner.gid = Some(Gid::from_raw(gid));
+        self
+    }
+
+    /// Set the supplementary groups of the process
+    pub fn groups(mut self, groups: &[u32]) -> Self {
+        self.inner.groups = Some(groups.iter().map(|gid| Gid::from_raw(*gid)).collect());
+        self
+    }
+
+    /// Run the process as the named user, with their primary and supplementary groups.
+    /// Also sets HOME, USER and LOGNAME to match
+    pub fn user(mut self, name: &str) -> Result<Self> {
+        let Some(user) = User::from_name(name)? else {
+            bail!("No such user '{}'", name);
+        };
+        let Ok(cname) = CString::new(name) else {
+            bail!("Unable to create CString from '{}'", name);
+        };
+        let groups = getgrouplist(&cname, user.gid)?;
+
+        self.inner.uid = Some(user.uid);
+        self.inner.gid = Some(user.gid);
+        self.inner.groups = Some(groups);
+        self.set_var("HOME", &user.dir.to_string_lossy())?;
+        self.set_var("USER", &user.name)?;
+        self.set_var("LOGNAME", &user.name)?;
+        Ok(self)
+    }
+
+    /// Add an environment value, replacing any existing value for the key
+    fn set_var(&mut self, key: &str, value: &str) -> Result<()> {
+        let var = EnvVar::from_str_pair(key, value)?;
+        self.inner.env.retain(|existing| existing.key != var.key);
+        self.inner.env.push(var);
+        Ok(())
+    }
+
+    /// Run a closure in the child after the ptys and all other options have been set up, right
+    /// before execve(). Closures run in the order they were added, and the first one to fail
+    /// aborts the spawn. Only the OS error code of a failure is passed back to the parent.
+    /// Unlike std's 'CommandExt::pre_exec' the closure is 'Fn', as an XCommand can be spawned
+    /// any number of times through a shared reference.
+    ///
+    /// # Safety
+    /// The closure runs in a forked copy of a possibly multithreaded process, so it may only do
+    /// what is async-signal-safe: no allocating, locking, logging or panicking. See
+    /// 'std::os::unix::process::CommandExt::pre_exec' for details
+    pub unsafe fn pre_exec<F>(mut self, f: F) -> Self
+    where
+        F: Fn() -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.inner.pre_exec.push(PreExecHook(Box::new(f)));
+        self
+    }
+
+    /// Choose how the process is created (default 'SpawnBackend::Auto')
+    pub fn backend(mut self, backend: SpawnBackend) -> Self {
+        self.inner.backend = backend;
+        self
+    }
+
+    /// Place 'fd' at fd number 'child_fd' in the process, e.g. an inherited listening socket.
+    /// 1 and 2 are taken by the stdout and stderr ptys
+    pub fn fd(mut self, child_fd: RawFd, fd: OwnedFd) -> Result<Self> {
+        self.check_child_fd(child_fd)?;
+        self.inner.fds.push((child_fd, fd));
+        Ok(self)
+    }
+
+    /// Create a pipe whose write end is at fd number 'child_fd' in the process. Lines written
+    /// to it are streamed as 'StdioType::SideChannel(child_fd)', next to stdout and stderr
+    pub fn side_channel(mut self, child_fd: RawFd) -> Result<Self> {
+        self.check_child_fd(child_fd)?;
+        self.inner.side_channels.push(child_fd);
+        Ok(self)
+    }
+
+    /// Give the process a pty as its stdin, which becomes its controlling terminal, so that it
+    /// can be driven with 'XChildHandle::expect()'. What it writes to the terminal is streamed
+    /// as 'StdioType::Terminal'. Requires the fork backend
+    pub fn pty_stdin(mut self, pty_stdin: bool) -> Self {
+        self.inner.pty_stdin = pty_stdin;
+        self
+    }
+
+    /// Read the output in the background as soon as the process starts, so that it never blocks
+    /// on a full pty while nothing is streaming its output. Each of its outputs is buffered
+    /// up to 'capacity' bytes, past which 'policy' applies. 'XChildHandle::drain_stats()' tells
+    /// how much was dropped or spilled
+    pub fn drain_output(mut self, policy: DrainPolicy, capacity: usize) -> Self {
+        self.inner.drain_output = Some((policy, capacity));
+        self
+    }
+
+    /// Copy each line of stdout to the file at 'path' as it is read, whether or not anything is
+    /// streaming the output, with secrets masked. This drains the output in the background, by
+    /// default keeping up to 1 MiB that hasn't been streamed in memory and spilling the rest to
+    /// a temp file, see 'drain_output()'. 'XChildHandle::expect()' then fails, as it can't be
+    /// used with a drained output
+    pub fn tee_stdout<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.inner
+            .tees
+            .push((TeeTarget::Stdout, path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Like 'tee_stdout()', for stderr
+    pub fn tee_stderr<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.inner
+            .tees
+            .push((TeeTarget::Stderr, path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Like 'tee_stdout()', for every output in the order it is read
+    pub fn tee_combined<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.inner
+            .tees
+            .push((TeeTarget::Combined, path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Prefixes, rotation and compression for every tee file
+    pub fn tee_options(mut self, options: TeeOptions) -> Self {
+        self.inner.tee_options = options;
+        self
+    }
+
+    /// Give the process's ptys a window size of 'cols' by 'rows', which can be changed later with
+    /// 'XChildHandle::resize()'. Without it their size is 0 by 0, which most programs take to
+    /// mean 80 by 24
+    pub fn window_size(mut self, cols: u16, rows: u16) -> Self {
+        self.inner.window_size = Some((cols, rows));
+        self
+    }
+
+    /// Record the session to 'path' as an asciinema v2 cast: everything written to the
+    /// process's ptys, what 'XExpect' sends it, every 'XChildHandle::resize()' and how it
+    /// finished, with secrets masked. The window size is our own terminal's, or 80 by 24,
+    /// unless given with 'window_size()'
+    pub fn record<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.inner.record = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Pass pre-bound listening sockets the way systemd's socket activation does: at fd 3 and
+    /// up, with LISTEN_FDS, LISTEN_FDNAMES and LISTEN_PID set. Each socket is named "unknown",
+    /// see 'named_listen_fds()'. Requires the fork backend, as LISTEN_PID has to be set in the
+    /// child
+    pub fn listen_fds(self, fds: Vec<OwnedFd>) -> Result<Self> {
+        let named = fds.into_iter().map(|fd| ("unknown", fd)).collect();
+        self.named_listen_fds(named)
+    }
+
+    /// Like 'listen_fds()', naming each socket for LISTEN_FDNAMES
+    pub fn named_listen_fds(mut self, fds: Vec<(&str, OwnedFd)>) -> Result<Self> {
+        if !self.inner.listen_fd_names.is_empty() {
+            bail!("Listening sockets have already been set");
+        }
+        if fds.is_empty() {
+            bail!("No listening sockets given");
+        }
+        const SD_LISTEN_FDS_START: RawFd = 3;
+        for (i, (name, fd)) in fds.into_iter().enumerate() {
+            if name.is_empty() || name.contains(':') {
+                bail!("Invalid listening socket name '{}'", name);
+            }
+            self = self.fd(SD_LISTEN_FDS_START + i as RawFd, fd)?;
+            self.inner.listen_fd_names.push(name.to_string());
+        }
+
+        let count = self.inner.listen_fd_names.len().to_string();
+        let names = self.inner.listen_fd_names.join(":");
+        self.set_var("LISTEN_FDS", &count)?;
+        self.set_var("LISTEN_FDNAMES", &names)?;
+        // Set in the child once its pid is known. Drop any we inherited
+        self.inner
+            .env
+            .retain(|var| var.key.as_bytes() != b"LISTEN_PID");
+        Ok(self)
+    }
+
+    fn check_child_fd(&self, child_fd: RawFd) -> Result<()> {
+        if child_fd < 0 || child_fd == libc::STDOUT_FILENO || child_fd == libc::STDERR_FILENO {
+            bail!(
+                "Unable to place a file descriptor at {} in the child",
+                child_fd
+            );
+        }
+        let taken = self.inner.fds.iter().map(|(fd, _)| fd);
+        if taken
+            .chain(&self.inner.side_channels)
+            .any(|fd| *fd == child_fd)
+        {
+            bail!("File descriptor {} in the child is already taken", child_fd);
+        }
+        Ok(())
+    }
+
     /// Build a XCommand
     pub fn build(self) -> XCommand {
-        XCommand::new(self.command, self.args, self.env)
+        self.inner
+    }
+}
+
+/// Apply a leading 'env [-i] NAME=value...' to 'env', returning the words after it. Left alone
+/// if no command follows, in which case 'env' is the command
+fn apply_env_prefix<'w>(env: &mut Vec<EnvVar>, words: &'w [Vec<u8>]) -> Result<&'w [Vec<u8>]> {
+    let Some((first, mut rest)) = words.split_first() else {
+        return Ok(words);
+    };
+    if first != b"env" {
+        return Ok(words);
+    }
+    let clear = rest.first().is_some_and(|word| word == b"-i");
+    if clear {
+        rest = &rest[1..];
+    }
+    let assignments: Vec<(&[u8], &[u8])> = rest
+        .iter()
+        .map_while(|word| match word.iter().position(|&byte| byte == b'=') {
+            Some(0) | None => None,
+            Some(i) => Some((&word[..i], &word[i + 1..])),
+        })
+        .collect();
+    if assignments.len() == rest.len() {
+        return Ok(words);
+    }
+
+    if clear {
+        env.clear();
+    }
+    for (key, value) in &assignments {
+        let (Ok(key), Ok(value)) = (CString::new(*key), CString::new(*value)) else {
+            bail!(
+                "Unable to create CString from the value of '{}'",
+                String::from_utf8_lossy(key)
+            );
+        };
+        env.retain(|existing| existing.key != key);
+        env.push(EnvVar { key, value });
+    }
+    Ok(&rest[assignments.len()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn secret_var_replaces_an_inherited_value() {
+        // Every test process inherits PATH
+        let command = XCommand::builder("/usr/bin/printenv")
+            .unwrap()
+            .arg("PATH")
+            .unwrap()
+            .secret_var("PATH", "/secret/bin")
+            .unwrap()
+            .build();
+        let paths = command
+            .env
+            .iter()
+            .filter(|var| var.key.as_bytes() == b"PATH");
+        assert_eq!(paths.count(), 1);
+
+        let mut child = command.spawn().unwrap();
+        let mut streamer = child.streamer().unwrap();
+        let lines: Vec<_> = streamer
+            .stream()
+            .map(|line| line.unwrap().1)
+            .collect()
+            .await;
+        assert_eq!(lines, ["/secret/bin"]);
     }
 }
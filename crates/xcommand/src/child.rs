@@ -0,0 +1,83 @@
+use crate::replay::{ReplayChild, ReplayStreamer};
+use crate::StdioType;
+use crate::XStatus;
+use crate::{XChildHandle, XStreamer};
+use eyre::Result;
+use std::future::Future;
+use tokio_stream::Stream;
+
+/// What XChildHandle and ReplayChild have in common, so that code consuming a child's output
+/// can be written once and tested against a replay instead of a real process
+pub trait XChild {
+    type Streamer: XChildStreamer;
+
+    /// See 'XChildHandle::streamer()'
+    fn streamer(&mut self) -> Result<Self::Streamer>;
+
+    /// See 'XChildHandle::status()'
+    fn status(&mut self) -> impl Future<Output = Result<XStatus>> + Send;
+
+    /// See 'XChildHandle::current_status()'
+    fn current_status(&self) -> XStatus;
+
+    /// See 'XChildHandle::status_changes()'
+    fn status_changes(&self) -> impl Stream<Item = XStatus> + Send + Unpin + 'static;
+}
+
+/// What XStreamer and ReplayStreamer have in common, see 'XChild'
+pub trait XChildStreamer {
+    /// See 'XStreamer::stream()'
+    fn stream(&mut self) -> impl Stream<Item = Result<(StdioType, String)>> + Send + Unpin + '_;
+}
+
+impl XChild for XChildHandle {
+    type Streamer = XStreamer;
+
+    fn streamer(&mut self) -> Result<XStreamer> {
+        XChildHandle::streamer(self)
+    }
+
+    fn status(&mut self) -> impl Future<Output = Result<XStatus>> + Send {
+        XChildHandle::status(self)
+    }
+
+    fn current_status(&self) -> XStatus {
+        XChildHandle::current_status(self)
+    }
+
+    fn status_changes(&self) -> impl Stream<Item = XStatus> + Send + Unpin + 'static {
+        XChildHandle::status_changes(self)
+    }
+}
+
+impl XChildStreamer for XStreamer {
+    fn stream(&mut self) -> impl Stream<Item = Result<(StdioType, String)>> + Send + Unpin + '_ {
+        XStreamer::stream(self)
+    }
+}
+
+impl XChild for ReplayChild {
+    type Streamer = ReplayStreamer;
+
+    fn streamer(&mut self) -> Result<ReplayStreamer> {
+        ReplayChild::streamer(self)
+    }
+
+    fn status(&mut self) -> impl Future<Output = Result<XStatus>> + Send {
+        ReplayChild::status(self)
+    }
+
+    fn current_status(&self) -> XStatus {
+        ReplayChild::current_status(self)
+    }
+
+    fn status_changes(&self) -> impl Stream<Item = XStatus> + Send + Unpin + 'static {
+        ReplayChild::status_changes(self)
+    }
+}
+
+impl XChildStreamer for ReplayStreamer {
+    fn stream(&mut self) -> impl Stream<Item = Result<(StdioType, String)>> + Send + Unpin + '_ {
+        ReplayStreamer::stream(self)
+    }
+}
@@ -0,0 +1,158 @@
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::unistd::pipe2;
+use std::fmt;
+use std::os::fd::AsRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::prelude::RawFd;
+
+/// Step of the child's setup between fork() and execve()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub(crate) enum ChildStage {
+    ParentDeathSignal = 1,
+    ResourceLimit,
+    Nice,
+    CpuAffinity,
+    IoPriority,
+    OomScoreAdj,
+    Groups,
+    Gid,
+    Uid,
+    PreExec,
+    Stdio,
+    Exec,
+    Session,
+    ControllingTerminal,
+}
+
+impl ChildStage {
+    fn from_raw(raw: i32) -> Option<Self> {
+        match raw {
+            1 => Some(Self::ParentDeathSignal),
+            2 => Some(Self::ResourceLimit),
+            3 => Some(Self::Nice),
+            4 => Some(Self::CpuAffinity),
+            5 => Some(Self::IoPriority),
+            6 => Some(Self::OomScoreAdj),
+            7 => Some(Self::Groups),
+            8 => Some(Self::Gid),
+            9 => Some(Self::Uid),
+            10 => Some(Self::PreExec),
+            11 => Some(Self::Stdio),
+            12 => Some(Self::Exec),
+            13 => Some(Self::Session),
+            14 => Some(Self::ControllingTerminal),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ChildStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self {
+            Self::ParentDeathSignal => "set the parent death signal",
+            Self::ResourceLimit => "set a resource limit",
+            Self::Nice => "set the niceness",
+            Self::CpuAffinity => "set the CPU affinity",
+            Self::IoPriority => "set the I/O priority",
+            Self::OomScoreAdj => "set the OOM score adjustment",
+            Self::Groups => "set the supplementary groups",
+            Self::Gid => "set the group id",
+            Self::Uid => "set the user id",
+            Self::PreExec => "run pre-exec hook",
+            Self::Stdio => "set up file descriptor",
+            Self::Exec => "execute it",
+            Self::Session => "start a new session",
+            Self::ControllingTerminal => "make the stdin pty its controlling terminal",
+        };
+        write!(f, "{}", stage)
+    }
+}
+
+/// A failure reported by the child before it could exec
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChildError {
+    pub stage: ChildStage,
+    pub errno: Errno,
+    /// Stage specific detail, e.g. the index of the resource limit that failed
+    pub index: usize,
+}
+
+/// Close-on-exec pipe the child uses to report setup failures to the parent. A successful
+/// execve() closes the write end without anything being written
+pub(crate) struct ErrorPipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+/// Size of a report on the pipe: stage, errno and index, as native endian i32s
+const REPORT_LEN: usize = 3 * 4;
+
+impl ErrorPipe {
+    /// The write end is placed at 'min_fd' or above
+    pub fn new(min_fd: RawFd) -> nix::Result<Self> {
+        let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+        if write.as_raw_fd() >= min_fd {
+            return Ok(Self { read, write });
+        }
+        let raw = fcntl(write.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(min_fd))?;
+        let write = unsafe { OwnedFd::from_raw_fd(raw) };
+        Ok(Self { read, write })
+    }
+
+    /// The write end, for the child to report on
+    pub fn writer(&self) -> RawFd {
+        self.write.as_raw_fd()
+    }
+
+    /// Block until the child has either exec'ed or reported a failure. Only to be called in the
+    /// parent
+    pub fn wait(self) -> Option<ChildError> {
+        let Self { read, write } = self;
+        // Otherwise we would never see EOF
+        drop(write);
+
+        let mut buf = [0u8; REPORT_LEN];
+        let mut filled = 0;
+        while filled < REPORT_LEN {
+            let res = unsafe {
+                libc::read(
+                    read.as_raw_fd(),
+                    buf[filled..].as_mut_ptr() as *mut libc::c_void,
+                    REPORT_LEN - filled,
+                )
+            };
+            match Errno::result(res) {
+                Ok(0) => break,
+                Ok(n) => filled += n as usize,
+                Err(Errno::EINTR) => continue,
+                Err(_) => break,
+            }
+        }
+        if filled < REPORT_LEN {
+            return None;
+        }
+
+        let field = |i: usize| i32::from_ne_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap());
+        Some(ChildError {
+            stage: ChildStage::from_raw(field(0))?,
+            errno: Errno::from_raw(field(1)),
+            index: field(2) as usize,
+        })
+    }
+}
+
+/// Write a report to the parent and exit. Only makes raw syscalls, so it is safe to call
+/// between fork() and execve()
+pub(crate) fn report(fd: RawFd, stage: ChildStage, errno: Errno, index: usize) -> ! {
+    let mut buf = [0u8; REPORT_LEN];
+    buf[0..4].copy_from_slice(&(stage as i32).to_ne_bytes());
+    buf[4..8].copy_from_slice(&(errno as i32).to_ne_bytes());
+    buf[8..12].copy_from_slice(&(index as i32).to_ne_bytes());
+    unsafe {
+        libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len());
+        libc::_exit(127);
+    }
+}
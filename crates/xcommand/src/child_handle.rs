@@ -1,85 +1,170 @@
+use crate::drain::{drain, DrainCounters, DrainPolicy, Drained, XDrainStats};
+use crate::exit::Reaped;
+use crate::expect::Pattern;
+use crate::expect::XExpect;
+use crate::hub::{Replay, XHub};
+use crate::monitor::monitor;
+use crate::ready::{OutputLog, Readiness};
+use crate::record::Recorder;
+use crate::secret::Secrets;
+use crate::tee::Tees;
 use crate::StdioType;
+use crate::XExit;
+use crate::XSample;
 use crate::XStatus;
 use async_stream::stream;
 use eyre::bail;
 use eyre::Result;
-use futures::pin_mut;
-use futures::TryStreamExt;
 use log::debug;
-use log::error;
-use log::info;
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
 use nix::sys::wait::WaitPidFlag;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{close, fork, Pid};
+use nix::unistd::Pid;
+use std::future::Future;
+use std::os::fd::AsRawFd;
+use std::os::fd::OwnedFd;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::AsyncBufReadExt;
 use tokio::io::BufReader;
-use tokio::sync::oneshot;
-use tokio::sync::oneshot::{Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio_fd::AsyncFd;
-use tokio_stream::wrappers::LinesStream;
+use tokio_stream::wrappers::{LinesStream, WatchStream};
 use tokio_stream::{Stream, StreamExt, StreamMap};
 
+/// How much of each output is kept in memory for the stream when tee files are written without
+/// 'XCommandBuilder::drain_output()'. The rest is spilled to disk
+const TEE_DRAIN_CAPACITY: usize = 1024 * 1024;
+
+/// The outputs of a newly spawned child, and how they are to be handled
+pub(crate) struct ChildOutputs {
+    /// Pty masters for stdout and stderr, followed by the read ends of any side channels
+    pub fds: Vec<(StdioType, OwnedFd)>,
+    /// For writing to the pty stdin
+    pub terminal: Option<OwnedFd>,
+    /// Another fd for each of the child's ptys, to resize them with
+    pub ptys: Vec<OwnedFd>,
+    pub drain_output: Option<(DrainPolicy, usize)>,
+    pub tees: Tees,
+    pub recorder: Option<Arc<Recorder>>,
+}
+
+/// What it takes to wait on the child and publish its state. Handed over by the XChildHandle to
+/// whichever of XStreamer or XExpect takes over the child's output
 #[derive(Debug)]
-pub struct XStreamer {
+pub(crate) struct Waiter {
     pid: Pid,
-    stdout: i32,
-    stderr: i32,
-    tx: Mutex<Option<Sender<XStatus>>>,
+    /// Publishes every state change of the child. Shared with the XChildHandle
+    status_tx: Arc<watch::Sender<XStatus>>,
+    /// Set once the child has been waited on. Shared with the XChildHandle
+    reaped: Arc<Reaped>,
+    /// Filled in when the child is reaped. Shared with the XChildHandle
+    exit: Arc<OnceLock<XExit>>,
+    started: SystemTime,
+    started_instant: Instant,
+    /// Told how the child finished, for the exit event of the recording
+    recorder: Option<Arc<Recorder>>,
 }
 
-impl XStreamer {
-    async fn send_status(&mut self, status: WaitStatus) {
-        let sender = {
-            let mut tx_lock = self.tx.lock().await;
-            tx_lock.take()
-        };
-        match sender {
-            Some(tx) => {
-                if tx.send(status.into()).is_err() {
-                    panic!("Unable to send");
-                };
+impl Waiter {
+    /// Wait for the child on the blocking pool, reporting job control transitions as they
+    /// happen. The waiter keeps running (and reaps the child) even if the handle is dropped
+    pub fn spawn(&self) -> JoinHandle<nix::Result<XStatus>> {
+        let pid = self.pid;
+        let status_tx = self.status_tx.clone();
+        let reaped = self.reaped.clone();
+        let exit = self.exit.clone();
+        let (started, started_instant) = (self.started, self.started_instant);
+        let recorder = self.recorder.clone();
+        tokio::task::spawn_blocking(move || loop {
+            let flags = WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+            let (status, usage) = reaped.wait4(pid, flags)?;
+            let status = XStatus::from(status);
+            if status.finished() {
+                let _ = exit.set(XExit::new(status, started, started_instant, &usage));
+                if let Some(recorder) = &recorder {
+                    recorder.exited(status);
+                }
+            } else {
+                debug!("Process {} changed state: {:?}", pid, status);
             }
-            None => {
-                panic!("bad bad bad");
+            status_tx.send_replace(status);
+            if status.finished() {
+                return Ok(status);
             }
-        }
+        })
+    }
+}
+
+/// Streams the output of a child process.
+/// Owns the pty masters and side channel pipes handed over by [`XChildHandle::streamer`]; they
+/// are closed when the streamer is dropped
+#[derive(Debug)]
+pub struct XStreamer {
+    pid: Pid,
+    outputs: Vec<(StdioType, OwnedFd)>,
+    /// Outputs read in the background, see 'XCommandBuilder::drain_output()'
+    drained: Vec<(StdioType, Drained)>,
+    /// Started along with the streamer, so that the child is reaped even if it is never
+    /// streamed. None once it has finished
+    waiter: Option<JoinHandle<nix::Result<XStatus>>>,
+    /// Every line passed on, for 'XChildHandle::wait_until_output()'
+    log: Arc<OutputLog>,
+    secrets: Secrets,
+    /// Whether to mask secrets in the output
+    redact: bool,
+}
+
+impl XStreamer {
+    /// Replace the values passed with 'secret_var()' and 'secret_arg()' with '***' in the
+    /// child's output. Output is matched line by line, so a secret that spans lines is missed
+    pub fn redact_secrets(&mut self, redact: bool) {
+        self.redact = redact;
     }
 
     fn _stream(&mut self) -> impl Stream<Item = Result<(StdioType, String)>> + '_ {
         stream! {
             let pid = self.pid;
 
-            let mut join = tokio::task::spawn(async move {
-                let Ok(status) = waitpid(pid, None) else {
-                    panic!("Error waiting for child to complete");
-                };
-                status
-            });
-
-            let stdout = AsyncFd::try_from(self.stdout).unwrap();
-            let stderr = AsyncFd::try_from(self.stderr).unwrap();
-            let mut stdout_reader = LinesStream::new(BufReader::new(stdout).lines());
-            let mut stderr_reader = LinesStream::new(BufReader::new(stderr).lines());
-
-            let stdout_stream = Box::pin(stream! {
-                while let Some(Ok(item)) = stdout_reader.next().await {
-                    yield item;
-                }
-            })
-                as Pin<Box<dyn Stream<Item = String> + Send>>;
-
-            let stderr_stream = Box::pin(stream! {
-                while let Some(Ok(item)) = stderr_reader.next().await {
-                    yield item;
-                }
-            })
-                as Pin<Box<dyn Stream<Item = String> + Send>>;
+            let Some(join) = self.waiter.as_mut() else {
+                yield Err(eyre::eyre!("The output of process {} has already been streamed to the end", pid));
+                return;
+            };
+            let log = self.log.clone();
+            let secrets = self.redact.then(|| self.secrets.clone()).filter(|secrets| !secrets.is_empty());
+            let redact = |(stdio, line): (StdioType, String)| match &secrets {
+                Some(secrets) => (stdio, secrets.redact(&line).into_owned()),
+                None => (stdio, line),
+            };
 
-            let mut map = StreamMap::with_capacity(2);
-            map.insert(StdioType::Stdout, stdout_stream);
-            map.insert(StdioType::Stderr, stderr_stream);
+            let mut map = StreamMap::with_capacity(self.outputs.len());
+            for (stdio, fd) in &self.outputs {
+                // The AsyncFds only borrow the descriptors, which stay owned by 'self'
+                let reader = match AsyncFd::try_from(fd.as_raw_fd()) {
+                    Ok(reader) => reader,
+                    Err(e) => {
+                        yield Err(e.into());
+                        return;
+                    }
+                };
+                let mut lines = LinesStream::new(BufReader::new(reader).lines());
+                let output = Box::pin(stream! {
+                    while let Some(Ok(item)) = lines.next().await {
+                        yield item;
+                    }
+                })
+                    as Pin<Box<dyn Stream<Item = String> + Send>>;
+                map.insert(*stdio, output);
+            }
+            for (stdio, buffer) in &self.drained {
+                let output = Box::pin(buffer.stream())
+                    as Pin<Box<dyn Stream<Item = String> + Send>>;
+                map.insert(*stdio, output);
+            }
 
             loop {
                 tokio::select! {
@@ -87,34 +172,25 @@ impl XStreamer {
                     // deadlocking when the command exits. - TODO: this might not be needed anymore
                     biased;
                     Some(output) = map.next() => {
-                        yield Ok(output);
+                        log.push(output.0, &output.1);
+                        yield Ok(redact(output));
                     },
-                    status = &mut join => {
-                        let status = status.unwrap(); // TODO: handle unwrap
-
+                    status = &mut *join => {
+                        self.waiter = None;
                         // Pick up any final output that was written in the time it took us to check
                         // this 'select!' branch
                         while let Some(output) = map.next().await {
-                            yield Ok(output);
+                            log.push(output.0, &output.1);
+                            yield Ok(redact(output));
                         }
-
-                        close(self.stdout).unwrap();
-                        close(self.stderr).unwrap();
-
-                        // TODO: do we need to handle any other WaitStatus variants?
-                        // https://docs.rs/nix/latest/nix/sys/wait/enum.WaitStatus.html
-
+                        log.close();
 
                         match status {
-                            WaitStatus::Exited(..) | WaitStatus::Signaled(..) | WaitStatus::Stopped(..) | WaitStatus::PtraceEvent(..) | WaitStatus::PtraceSyscall(..) | WaitStatus::Continued(..) => {
-                                // Send status code back to child handle
-                                self.send_status(status).await;
-                                return;
-                            },
-                            WaitStatus::StillAlive => {
-                                panic!("Child process in unexpected state: '{:?}'", status);
-                            },
+                            Ok(Ok(_)) => {}
+                            Ok(Err(e)) => yield Err(eyre::eyre!("Unable to wait on process {}: {}", pid, e)),
+                            Err(e) => yield Err(e.into()),
                         }
+                        return;
                     },
                 }
             }
@@ -125,47 +201,159 @@ impl XStreamer {
         let stream = self._stream();
         Box::pin(stream)
     }
-}
 
-#[derive(Debug)]
-enum StatusWrapper {
-    Init(XStatus),
-    Received(XStatus),
+    /// Hand the output over to a hub, for more than one consumer. Must be called within a tokio
+    /// runtime
+    pub fn hub(self, replay: Replay) -> XHub {
+        XHub::new(self.pid, self, replay)
+    }
 }
 
 #[derive(Debug)]
 pub struct XChildHandle {
     pid: Pid,
-    /// Raw file descriptor
-    stdout: i32,
-    /// Raw file descriptor
-    stderr: i32,
+    /// Pty masters for the child's stdout and stderr, followed by the read ends of any side
+    /// channels. Moved into the XStreamer by 'streamer()'
+    outputs: Option<Vec<(StdioType, OwnedFd)>>,
+    /// Outputs that are being read in the background instead. Moved into the XStreamer
+    drained: Vec<(StdioType, Drained)>,
+    drain_counters: Option<Arc<DrainCounters>>,
+
+    /// For writing to the pty stdin given with 'pty_stdin()'. Moved into the XExpect
+    terminal: Option<OwnedFd>,
+    /// For 'resize()', if the child was given a window size
+    ptys: Vec<OwnedFd>,
+    recorder: Option<Arc<Recorder>>,
+    /// Moved into the XStreamer or XExpect, which is responsible for waiting on the child
+    status_tx: Option<Arc<watch::Sender<XStatus>>>,
+    status_rx: watch::Receiver<XStatus>,
 
-    rx: Mutex<Option<Receiver<XStatus>>>,
-    status: StatusWrapper,
+    kill_on_drop: bool,
+    /// Set once the child has been waited on, after which its pid may belong to someone else
+    reaped: Arc<Reaped>,
+    exit: Arc<OnceLock<XExit>>,
+    started: SystemTime,
+    started_instant: Instant,
+    /// Handed to the XStreamer for 'redact_secrets()'
+    secrets: Secrets,
+    /// The latest lines passed on by the XStreamer, or read by the XExpect
+    log: Arc<OutputLog>,
 }
 
 impl XChildHandle {
-    pub fn streamer(&mut self) -> XStreamer {
-        let (tx, rx) = oneshot::channel();
-        self.rx = Mutex::new(Some(rx));
-        XStreamer {
+    /// Get a streamer for the child's output.
+    /// The streamer takes ownership of the pty masters, so it can only be created once. See
+    /// 'hub()' for more than one consumer. Must be called within a tokio runtime, as the child
+    /// is waited on from then on
+    pub fn streamer(&mut self) -> Result<XStreamer> {
+        let (outputs, waiter) = self.take_outputs()?;
+        Ok(XStreamer {
             pid: self.pid,
-            stdout: self.stdout,
-            stderr: self.stderr,
-            tx: Mutex::new(Some(tx)),
+            outputs,
+            drained: std::mem::take(&mut self.drained),
+            waiter: Some(waiter.spawn()),
+            log: self.log.clone(),
+            secrets: self.secrets.clone(),
+            redact: false,
+        })
+    }
+
+    /// Get a hub that fans the child's output out to any number of subscribers, replaying
+    /// recent output to late ones. Like 'streamer()', it can only be created once. Must be
+    /// called within a tokio runtime
+    pub fn hub(&mut self, replay: Replay) -> Result<XHub> {
+        Ok(self.streamer()?.hub(replay))
+    }
+
+    /// Interact with the child like expect(1) does. Takes over the child's output, so it can't
+    /// be used along with 'streamer()'. Input can only be sent to a child spawned with
+    /// 'XCommandBuilder::pty_stdin()'
+    pub fn expect(&mut self) -> Result<XExpect> {
+        if self.drain_counters.is_some() {
+            bail!(
+                "The output of process {} is drained in the background, which 'expect()' can't be used with",
+                self.pid
+            );
         }
+        let (outputs, waiter) = self.take_outputs()?;
+        let recorder = self.recorder.clone();
+        let log = self.log.clone();
+        XExpect::new(
+            self.pid,
+            outputs,
+            self.terminal.take(),
+            waiter,
+            recorder,
+            log,
+            self.secrets.clone(),
+        )
     }
 
-    // TODO: I don't want this to be pub, but XCommand might need to call it
-    pub fn new(pid: Pid, stdout: i32, stderr: i32) -> Result<Self> {
-        let status = StatusWrapper::Init(XStatus::Running);
+    fn take_outputs(&mut self) -> Result<(Vec<(StdioType, OwnedFd)>, Waiter)> {
+        let (Some(outputs), Some(status_tx)) = (self.outputs.take(), self.status_tx.take()) else {
+            bail!(
+                "The output of process {} is already being streamed",
+                self.pid
+            );
+        };
+        let waiter = Waiter {
+            pid: self.pid,
+            status_tx,
+            reaped: self.reaped.clone(),
+            exit: self.exit.clone(),
+            started: self.started,
+            started_instant: self.started_instant,
+            recorder: self.recorder.clone(),
+        };
+        Ok((outputs, waiter))
+    }
+
+    pub(crate) fn new(
+        pid: Pid,
+        outputs: ChildOutputs,
+        kill_on_drop: bool,
+        secrets: Secrets,
+    ) -> Result<Self> {
+        let (status_tx, status_rx) = watch::channel(XStatus::Running);
+        let ChildOutputs {
+            fds,
+            terminal,
+            ptys,
+            drain_output,
+            tees,
+            recorder,
+        } = outputs;
+        // Tee files are written as the output is drained, so that they don't depend on anything
+        // streaming it. Spilling neither loses lines nor blocks the child on a slow stream
+        let drain_output = match drain_output {
+            None if !tees.is_empty() => Some((DrainPolicy::Spill, TEE_DRAIN_CAPACITY)),
+            drain_output => drain_output,
+        };
+        let (outputs, drained, drain_counters) = match drain_output {
+            Some((policy, capacity)) => {
+                let counters = Arc::new(DrainCounters::default());
+                let drained = drain(fds, &tees, policy, capacity, &counters)?;
+                (Vec::new(), drained, Some(counters))
+            }
+            None => (fds, Vec::new(), None),
+        };
         Ok(XChildHandle {
             pid,
-            stdout,
-            stderr,
-            status,
-            rx: Mutex::new(None),
+            outputs: Some(outputs),
+            drained,
+            drain_counters,
+            terminal,
+            ptys,
+            recorder,
+            status_tx: Some(Arc::new(status_tx)),
+            status_rx,
+            kill_on_drop,
+            reaped: Arc::new(Reaped::default()),
+            exit: Arc::new(OnceLock::new()),
+            started: SystemTime::now(),
+            started_instant: Instant::now(),
+            secrets,
+            log: Arc::new(OutputLog::new()),
         })
     }
 
@@ -173,29 +361,196 @@ impl XChildHandle {
         self.pid
     }
 
+    pub(crate) fn set_kill_on_drop(&mut self, kill_on_drop: bool) {
+        self.kill_on_drop = kill_on_drop;
+    }
+
+    /// Wait for the child to exit. The child is waited on by its XStreamer or XExpect, so
+    /// 'streamer()' or 'expect()' must have been called first
     pub async fn status(&mut self) -> Result<XStatus> {
-        match &self.status {
-            StatusWrapper::Init(initial_status) => {
-                let receiver = {
-                    let mut rx_opt = self.rx.lock().await;
-                    rx_opt.take()
-                };
+        if self.status_tx.is_some() {
+            bail!(
+                "Nothing is waiting on process {}. Call 'streamer()' or 'expect()' first",
+                self.pid
+            );
+        }
+        match self.status_rx.wait_for(XStatus::finished).await {
+            Ok(status) => Ok(*status),
+            Err(_) => bail!("Stopped waiting on process {} before it exited", self.pid),
+        }
+    }
 
-                match receiver {
-                    Some(rx) => match rx.await {
-                        Ok(status) => {
-                            self.status = StatusWrapper::Received(status.clone());
-                            Ok(status)
-                        }
-                        Err(e) => bail!("Unable to get status: {:?}", e),
-                    },
-                    None => {
-                        bail!("todo: error message");
-                    }
-                }
-            }
+    /// Wait for the child to exit, returning its status along with timing and resource usage
+    pub async fn exit(&mut self) -> Result<XExit> {
+        self.status().await?;
+        match self.exit.get() {
+            Some(exit) => Ok(exit.clone()),
+            None => bail!("Resource usage of process {} was not recorded", self.pid),
+        }
+    }
+
+    /// Sample the child's CPU, memory, thread, fd and I/O usage from /proc every 'interval'.
+    /// The stream ends when the child exits
+    pub fn monitor(&self, interval: Duration) -> impl Stream<Item = Result<XSample>> {
+        monitor(self.pid, interval, false, self.reaped.clone())
+    }
+
+    /// Like 'monitor()', but each sample totals the child and all of its descendants
+    pub fn monitor_tree(&self, interval: Duration) -> impl Stream<Item = Result<XSample>> {
+        monitor(self.pid, interval, true, self.reaped.clone())
+    }
+
+    /// Wait up to 'timeout' for a line of output matching 'pattern', a literal string or a
+    /// regex::Regex, and return it. Lines are seen as the XStreamer passes them on, so its
+    /// stream has to be kept polling, e.g. on a task of its own; nothing is taken away from it.
+    /// With 'expect()' they are seen as soon as they are read. Lines output before the call
+    /// count too. Fails with the latest output if the child finishes first
+    pub async fn wait_until_output(
+        &self,
+        pattern: impl Into<Pattern>,
+        timeout: Duration,
+    ) -> Result<String> {
+        self.readiness()?.output(pattern.into(), timeout).await
+    }
+
+    /// Wait up to 'timeout' for a connection to 'port' on localhost to be accepted
+    pub async fn wait_for_port(&self, port: u16, timeout: Duration) -> Result<()> {
+        let what = format!("port {} to accept connections", port);
+        self.readiness()?
+            .probe(&what, timeout, || async move {
+                TcpStream::connect(("localhost", port)).await.ok().map(drop)
+            })
+            .await
+    }
+
+    /// Wait up to 'timeout' for a connection to the unix socket at 'path' to be accepted
+    pub async fn wait_for_unix_socket<P: AsRef<Path>>(
+        &self,
+        path: P,
+        timeout: Duration,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let what = format!("'{}' to accept connections", path.display());
+        self.readiness()?
+            .probe(&what, timeout, || async move {
+                UnixStream::connect(path).await.ok().map(drop)
+            })
+            .await
+    }
+
+    /// Wait up to 'timeout' for a file, directory or socket to appear at 'path'
+    pub async fn wait_for_path<P: AsRef<Path>>(&self, path: P, timeout: Duration) -> Result<()> {
+        let path = path.as_ref();
+        let what = format!("'{}' to exist", path.display());
+        self.readiness()?
+            .probe(
+                &what,
+                timeout,
+                || async move { path.exists().then_some(()) },
+            )
+            .await
+    }
 
-            StatusWrapper::Received(status) => Ok(status.clone()),
+    /// Wait up to 'timeout' for 'probe' to return true. It is retried every 50ms
+    pub async fn wait_until<F, Fut>(&self, mut probe: F, timeout: Duration) -> Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        self.readiness()?
+            .probe("the readiness probe to pass", timeout, || {
+                let ready = probe();
+                async move { ready.await.then_some(()) }
+            })
+            .await
+    }
+
+    /// The readiness waits rely on the XStreamer or XExpect to notice the child dying
+    fn readiness(&self) -> Result<Readiness<'_>> {
+        if self.status_tx.is_some() {
+            bail!(
+                "Nothing is waiting on process {}. Call 'streamer()' or 'expect()' first",
+                self.pid
+            );
+        }
+        Ok(Readiness {
+            pid: self.pid,
+            status_rx: self.status_rx.clone(),
+            log: &self.log,
+            secrets: &self.secrets,
+        })
+    }
+
+    /// Set the window size of the child's ptys, which must have been given one with
+    /// 'XCommandBuilder::window_size()' or 'record()', and send it SIGWINCH
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<()> {
+        if self.ptys.is_empty() {
+            bail!(
+                "Process {} has no window size to change, see 'XCommandBuilder::window_size()'",
+                self.pid
+            );
+        }
+        let size = libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        for pty in &self.ptys {
+            let res = unsafe { libc::ioctl(pty.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+            Errno::result(res)?;
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.resize(cols, rows);
+        }
+        // The kernel only signals the process group a pty is the controlling terminal of
+        self.reaped.kill(self.pid, Signal::SIGWINCH)?;
+        Ok(())
+    }
+
+    /// How much output had to be dropped or spilled to disk so far, if it is drained in the
+    /// background, see 'XCommandBuilder::drain_output()'
+    pub fn drain_stats(&self) -> Option<XDrainStats> {
+        self.drain_counters
+            .as_ref()
+            .map(|counters| counters.stats())
+    }
+
+    /// The most recently reported state of the child, without waiting
+    pub fn current_status(&self) -> XStatus {
+        *self.status_rx.borrow()
+    }
+
+    /// Stream of state changes of the child, including job control stops and continues.
+    /// Starts with the current state. Changes that happen faster than the stream is polled are
+    /// coalesced into the latest one
+    pub fn status_changes(&self) -> impl Stream<Item = XStatus> {
+        WatchStream::new(self.status_rx.clone())
+    }
+}
+
+impl Drop for XChildHandle {
+    fn drop(&mut self) {
+        if !self.kill_on_drop {
+            return;
+        }
+        if !matches!(self.reaped.kill(self.pid, Signal::SIGKILL), Ok(true)) {
+            return;
+        }
+        debug!("Killed process {} on drop", self.pid);
+        // Without a streamer nobody else is going to wait on the child, so reap it ourselves
+        // rather than leave a zombie behind
+        if self.status_tx.is_some() {
+            let pid = self.pid;
+            let reaped = self.reaped.clone();
+            let recorder = self.recorder.clone();
+            std::thread::spawn(move || {
+                let (status, _) = reaped.wait4(pid, WaitPidFlag::empty())?;
+                if let Some(recorder) = recorder {
+                    recorder.exited(XStatus::from(status));
+                }
+                nix::Result::Ok(())
+            });
         }
     }
 }
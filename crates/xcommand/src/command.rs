@@ -1,129 +1,497 @@
+use crate::backend::posix_spawn;
 use crate::builder::XCommandBuilder;
-use crate::child_handle::XChildHandle;
+use crate::child_error::{report, ChildError, ChildStage, ErrorPipe};
+use crate::child_handle::{ChildOutputs, XChildHandle};
+use crate::drain::DrainPolicy;
 use crate::env_var::EnvVar;
-use async_stream::stream;
+use crate::exec_args::ExecArgs;
+use crate::parse::{quote, quote_bytes};
+use crate::record::{tap, Recorder, DEFAULT_WINDOW_SIZE};
+use crate::scheduling::{set_io_priority, set_nice, set_oom_score_adj};
+use crate::secret::Secrets;
+use crate::tee::{TeeOptions, TeeTarget, Tees};
+use crate::IoPriority;
+use crate::SpawnBackend;
+use crate::StdioType;
 use eyre::bail;
 use eyre::Result;
 use log::debug;
-use log::error;
-use log::info;
-use nix::pty::openpty;
-use nix::pty::OpenptyResult;
-use nix::sys::signal::Signal;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::dup2;
-use nix::unistd::execve;
-use nix::unistd::pipe;
-use nix::unistd::setsid;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::pty::{openpty, Winsize};
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{raise, Signal};
+use nix::sys::wait::waitpid;
 use nix::unistd::ForkResult;
-use nix::unistd::{close, fork, Pid};
+use nix::unistd::{dup2, pipe2};
+use nix::unistd::{fork, getpid, getppid, setgid, setgroups, setsid, setuid, Gid, Pid, Uid};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env;
 use std::ffi::CString;
-use std::fs::File;
-use std::mem;
+use std::fmt;
+use std::io;
 use std::os::fd::AsRawFd;
 use std::os::fd::OwnedFd;
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::prelude::RawFd;
-use std::path::Path;
-use std::pin::Pin;
-use tokio::io::AsyncBufReadExt;
-use tokio::io::BufReader;
-use tokio_fd::AsyncFd;
-use tokio_stream::wrappers::LinesStream;
-use tokio_stream::{Stream, StreamExt, StreamMap};
-use which::which;
-
-#[derive(Debug)]
+use std::path::{Path, PathBuf};
+use terminal_size::{Height, Width};
+
+/// Open a pty, marking both ends close-on-exec so that they are not inherited by any other
+/// children we spawn concurrently. The child's copies of the slave are dup'ed onto its stdio,
+/// which clears the flag on the new descriptors.
+pub(crate) fn open_pty(size: Option<(u16, u16)>) -> Result<(OwnedFd, OwnedFd)> {
+    let size = size.map(|(cols, rows)| Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    });
+    let res = openpty(size.as_ref(), None)?;
+    for fd in [&res.master, &res.slave] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    Ok((res.master, res.slave))
+}
+
+/// Duplicate 'fd' to the lowest free fd number that is at least 'min', close-on-exec
+fn dup_above(fd: &OwnedFd, min: RawFd) -> Result<OwnedFd> {
+    let raw = fcntl(fd.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(min))?;
+    Ok(unsafe { OwnedFd::from_raw_fd(raw) })
+}
+
+/// Where the child's stdout or stderr goes
+pub(crate) enum Sink {
+    /// A pty that is streamed as the given type, shared by stdout and stderr if both go to the
+    /// same type
+    Pty(StdioType),
+    Fd(OwnedFd),
+}
+
+/// The child's stdio: the parent's stdin unless replaced, and ptys for stdout and stderr by
+/// default. Changed for the stages of an XPipeline and redirections in an XScript
+pub(crate) struct StageIo {
+    pub stdin: Option<OwnedFd>,
+    pub stdout: Sink,
+    pub stderr: Sink,
+}
+
+impl Default for StageIo {
+    fn default() -> Self {
+        Self {
+            stdin: None,
+            stdout: Sink::Pty(StdioType::Stdout),
+            stderr: Sink::Pty(StdioType::Stderr),
+        }
+    }
+}
+
+/// The fd to place at the child's stdout or stderr
+fn sink_fd<'a>(sink: &'a Sink, slaves: &'a [(StdioType, OwnedFd)]) -> &'a OwnedFd {
+    match sink {
+        // A pty was opened for every type a sink goes to
+        Sink::Pty(stdio) => slaves
+            .iter()
+            .find(|(opened, _)| opened == stdio)
+            .map(|(_, slave)| slave)
+            .unwrap(),
+        Sink::Fd(fd) => fd,
+    }
+}
+
+/// Closure run in the child right before execve()
+pub(crate) struct PreExecHook(pub(crate) Box<dyn Fn() -> io::Result<()> + Send + Sync>);
+
+impl fmt::Debug for PreExecHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PreExecHook")
+    }
+}
+
 pub struct XCommand {
-    command: CString,
-    args: Vec<CString>,
-    env: Vec<EnvVar>,
+    pub(crate) command: CString,
+    pub(crate) args: Vec<CString>,
+    pub(crate) env: Vec<EnvVar>,
+    pub(crate) kill_on_drop: bool,
+    pub(crate) parent_death_signal: Option<Signal>,
+    /// (resource, soft, hard)
+    pub(crate) rlimits: Vec<(Resource, u64, u64)>,
+    pub(crate) nice: Option<i32>,
+    pub(crate) cpu_affinity: Option<CpuSet>,
+    pub(crate) io_priority: Option<IoPriority>,
+    pub(crate) oom_score_adj: Option<i32>,
+    pub(crate) uid: Option<Uid>,
+    pub(crate) gid: Option<Gid>,
+    pub(crate) groups: Option<Vec<Gid>>,
+    pub(crate) pre_exec: Vec<PreExecHook>,
+    pub(crate) backend: SpawnBackend,
+    /// (fd number in the child, fd to place there)
+    pub(crate) fds: Vec<(RawFd, OwnedFd)>,
+    /// fd numbers in the child of pipes to stream from
+    pub(crate) side_channels: Vec<RawFd>,
+    /// Names of the sockets passed with 'listen_fds()', which are in 'fds' at 3 and up
+    pub(crate) listen_fd_names: Vec<String>,
+    /// Values of secret vars and args, masked wherever the command is shown
+    pub(crate) secrets: Secrets,
+    /// Give the child a pty as its stdin and controlling terminal
+    pub(crate) pty_stdin: bool,
+    /// Read the output in the background into buffers of this many bytes
+    pub(crate) drain_output: Option<(DrainPolicy, usize)>,
+    /// Files to copy the output to, see 'tee_stdout()'
+    pub(crate) tees: Vec<(TeeTarget, PathBuf)>,
+    pub(crate) tee_options: TeeOptions,
+    /// (columns, rows) of the child's ptys
+    pub(crate) window_size: Option<(u16, u16)>,
+    /// Where to record the session as an asciinema cast
+    pub(crate) record: Option<PathBuf>,
 }
 
 impl XCommand {
     // TODO: this should be like std::process::Command and new() should only take in command name. XCommandBuilder should have public access and set struct fields
     pub fn new(command: CString, args: Vec<CString>, env: Vec<EnvVar>) -> Self {
-        Self { command, args, env }
+        Self {
+            command,
+            args,
+            env,
+            kill_on_drop: false,
+            parent_death_signal: None,
+            rlimits: Vec::new(),
+            nice: None,
+            cpu_affinity: None,
+            io_priority: None,
+            oom_score_adj: None,
+            uid: None,
+            gid: None,
+            groups: None,
+            pre_exec: Vec::new(),
+            backend: SpawnBackend::default(),
+            fds: Vec::new(),
+            side_channels: Vec::new(),
+            listen_fd_names: Vec::new(),
+            secrets: Secrets::default(),
+            pty_stdin: false,
+            drain_output: None,
+            tees: Vec::new(),
+            tee_options: TeeOptions::default(),
+            window_size: None,
+            record: None,
+        }
     }
     pub fn builder<P: AsRef<Path>>(command: P) -> Result<XCommandBuilder> {
         XCommandBuilder::new(command)
     }
 
-    /// Replace the current process with the executed command
-    fn exec(&self) -> Result<()> {
-        // Prepend the comnand name to the array of args
-        let mut args = self.args.clone();
-        args.insert(0, self.command.clone());
+    /// Build a command from a command line, quoted and escaped as for a POSIX shell, without
+    /// running one. '$VAR' and '${VAR:-default}' are expanded against the parent's environment,
+    /// which the command inherits. Syntax errors are a 'ParseError'
+    pub fn parse(line: &str) -> Result<XCommandBuilder> {
+        XCommandBuilder::from_command_line(line, true)
+    }
+
+    /// Like 'parse()', but with '$' taken literally
+    pub fn parse_literal(line: &str) -> Result<XCommandBuilder> {
+        XCommandBuilder::from_command_line(line, false)
+    }
 
-        // Format each variable as 'key=value'
-        let env: Vec<CString> = self
+    /// The command as a POSIX shell command line that can be pasted into a terminal. Variables
+    /// that differ from the parent's environment are set with 'env', which starts from an empty
+    /// environment with '-i' if any of the parent's were left out, and which 'parse()' takes
+    /// back. Words that aren't valid UTF-8 are written in '$'...'' quotes, and secrets are masked
+    pub fn to_shell_string(&self) -> String {
+        let parent: HashMap<Vec<u8>, Vec<u8>> = env::vars_os()
+            .map(|(key, value)| (key.into_vec(), value.into_vec()))
+            .collect();
+        let removed = parent
+            .keys()
+            .any(|key| !self.env.iter().any(|var| var.key.as_bytes() == key));
+        let changed: Vec<&EnvVar> = self
             .env
             .iter()
-            .map(|var| {
-                let k = var.key.clone();
-                let v = var.value.clone();
-                let key_bytes = k.as_bytes();
-                let eq_bytes = "=".as_bytes();
-                let value_bytes = v.as_bytes();
-                let mut formatted =
-                    Vec::with_capacity(key_bytes.len() + value_bytes.len() + eq_bytes.len());
-                formatted.extend_from_slice(key_bytes);
-                formatted.extend_from_slice(eq_bytes);
-                formatted.extend_from_slice(value_bytes);
-                CString::new(formatted).unwrap()
+            .filter(|var| {
+                removed
+                    || parent.get(var.key.as_bytes()).map(Vec::as_slice)
+                        != Some(var.value.as_bytes())
             })
             .collect();
 
-        // Cannot call println or unwrap in child - see
-        // https://docs.rs/nix/0.25.0/nix/unistd/fn.fork.html#safety
-        //nix::unistd::write(libc::STDOUT_FILENO, "I'm a new child process - stdout\n".as_bytes()).ok();
-        //nix::unistd::write(libc::STDERR_FILENO, "I'm a new child process - stderr\n".as_bytes()).ok();
-
-        match execve(&self.command, &args, &env) {
-            Ok(_) => {}
-            Err(e) => {
-                bail!(
-                    "Unable to execve command '{:?}' with args {:?} and environment {:?}. Reason: {}",
-                    self.command,
-                    args,
-                    env,
-                    e
-                );
+        let mut words = Vec::new();
+        if removed || !changed.is_empty() {
+            words.push("env".to_string());
+            if removed {
+                words.push("-i".to_string());
+            }
+            for var in changed {
+                let key = quote_bytes(var.key.as_bytes());
+                words.push(format!("{}={}", key, self.shell_word(&var.value)));
             }
         }
-        Ok(())
+        for word in std::iter::once(&self.command).chain(&self.args) {
+            words.push(self.shell_word(word));
+        }
+        words.join(" ")
+    }
+
+    /// 'word' quoted for a shell, with secrets masked
+    fn shell_word(&self, word: &CString) -> String {
+        match self.secrets.redact(&word.to_string_lossy()) {
+            // The word isn't given back as it was anyway
+            Cow::Owned(redacted) => quote(&redacted).into_owned(),
+            Cow::Borrowed(_) => quote_bytes(word.as_bytes()).into_owned(),
+        }
+    }
+
+    /// Describe a failure the child reported before it could exec
+    fn describe_child_error(&self, error: ChildError) -> String {
+        let detail = match error.stage {
+            ChildStage::ResourceLimit => match self.rlimits.get(error.index) {
+                Some((resource, soft, hard)) => {
+                    format!(" {:?} (soft {}, hard {})", resource, soft, hard)
+                }
+                None => String::new(),
+            },
+            ChildStage::Nice => format!(" to {}", self.nice.unwrap_or_default()),
+            ChildStage::IoPriority => format!(" to {:?}", self.io_priority),
+            ChildStage::OomScoreAdj => format!(" to {}", self.oom_score_adj.unwrap_or_default()),
+            ChildStage::Uid => format!(" to {}", self.uid.unwrap_or(Uid::current())),
+            ChildStage::Gid => format!(" to {}", self.gid.unwrap_or(Gid::current())),
+            ChildStage::Groups => format!(" to {:?}", self.groups.as_deref().unwrap_or_default()),
+            ChildStage::PreExec => format!(" #{}", error.index),
+            ChildStage::Stdio => format!(" {}", error.index),
+            ChildStage::ParentDeathSignal
+            | ChildStage::CpuAffinity
+            | ChildStage::Session
+            | ChildStage::ControllingTerminal
+            | ChildStage::Exec => String::new(),
+        };
+        format!(
+            "Unable to spawn '{}': failed to {}{}: {}",
+            self.command.to_string_lossy(),
+            error.stage,
+            detail,
+            error.errno.desc()
+        )
     }
 
     pub fn spawn(&self) -> Result<XChildHandle> {
-        debug!(
-            "Running '{:?}' with args {:?} and env {:?}",
-            self.command, self.args, self.env
-        );
-        // Open two ptys, one for stdout and one for stderr
+        self.spawn_with(StageIo::default())
+    }
+
+    /// Spawn with the stdin and stdout of a pipeline stage
+    pub(crate) fn spawn_with(&self, io: StageIo) -> Result<XChildHandle> {
+        debug!("Running {}", self);
+        // Open two ptys, one for stdout and one for stderr, unless they go elsewhere
         // This seems ludicrous however I cannot find a way to seprately send both streams and
         // fake a pty.
         // This SO question summs it up
         // https://stackoverflow.com/questions/34186035/can-you-fool-isatty-and-log-stdout-and-stderr-separately
 
-        let res = openpty(None, None)?;
-        let master = res.master;
-        let slave = res.slave;
-        let stdout_master = master.as_raw_fd();
-        let stdout_slave = slave.as_raw_fd();
-        // Stop drop from closing descriptors
-        mem::forget(master);
-        mem::forget(slave);
-
-        let res = openpty(None, None)?;
-        let master = res.master;
-        let slave = res.slave;
-        let stderr_master = master.as_raw_fd();
-        let stderr_slave = slave.as_raw_fd();
-        // Stop drop from closing descriptors
-        mem::forget(master);
-        mem::forget(slave);
+        // Before anything is started, so that a bad path doesn't leave a child behind
+        let tees = Tees::open(&self.tees, &self.tee_options, &self.secrets)?;
+        // A recording needs a size, which is our own terminal's if it isn't given
+        let window_size = self.window_size.or_else(|| {
+            self.record
+                .as_ref()
+                .map(|_| match terminal_size::terminal_size() {
+                    Some((Width(cols), Height(rows))) => (cols, rows),
+                    None => DEFAULT_WINDOW_SIZE,
+                })
+        });
+        let recorder = match &self.record {
+            Some(path) => Some(Recorder::create(
+                path,
+                window_size.unwrap_or(DEFAULT_WINDOW_SIZE),
+                &self.to_shell_string(),
+                &self.recorded_env(),
+                self.secrets.clone(),
+            )?),
+            None => None,
+        };
+        // Kept for resizing, only when there's a size to change, as an open master keeps the
+        // child's writes from failing once its output is no longer read
+        let mut ptys = Vec::new();
+        let mut keep_pty = |master: &OwnedFd| -> Result<()> {
+            if window_size.is_some() {
+                ptys.push(dup_above(master, 0)?);
+            }
+            Ok(())
+        };
+
+        let mut outputs = Vec::with_capacity(2 + self.side_channels.len());
+        let mut slaves: Vec<(StdioType, OwnedFd)> = Vec::with_capacity(2);
+        for sink in [&io.stdout, &io.stderr] {
+            if let Sink::Pty(stdio) = sink {
+                if !slaves.iter().any(|(opened, _)| opened == stdio) {
+                    let (master, slave) = open_pty(window_size)?;
+                    keep_pty(&master)?;
+                    outputs.push((*stdio, master));
+                    slaves.push((*stdio, slave));
+                }
+            }
+        }
+        let (stdout, stderr) = (sink_fd(&io.stdout, &slaves), sink_fd(&io.stderr, &slaves));
+
+        // Everything the child needs is prepared up front. After fork() it may only make raw
+        // syscalls: no allocating, logging or panicking, as another thread may have held the
+        // allocator's or logger's lock at the time of the fork
+        let pid_var = (!self.listen_fd_names.is_empty()).then_some("LISTEN_PID");
+        let mut exec_args = ExecArgs::new(&self.command, &self.args, &self.env, pid_var);
+
+        // Every fd handed to the child is first moved above all of the fd numbers it is going
+        // to be placed at, so that placing one can't clobber another that is still waiting
+        let min_fd = self.max_child_fd() + 1;
+        let mut child_fds = Vec::with_capacity(self.fds.len() + self.side_channels.len() + 1);
+        if let Some(stdin) = &io.stdin {
+            child_fds.push((libc::STDIN_FILENO, dup_above(stdin, min_fd)?));
+        }
+        // The master is both streamed from and written to, through separate fds
+        let mut terminal = None;
+        if self.pty_stdin {
+            if io.stdin.is_some() {
+                bail!("'pty_stdin' can't be used with a redirected stdin");
+            }
+            let (master, slave) = open_pty(window_size)?;
+            keep_pty(&master)?;
+            terminal = Some(dup_above(&master, 0)?);
+            outputs.push((StdioType::Terminal, master));
+            child_fds.push((libc::STDIN_FILENO, dup_above(&slave, min_fd)?));
+        }
+        for (child_fd, fd) in &self.fds {
+            child_fds.push((*child_fd, dup_above(fd, min_fd)?));
+        }
+        for child_fd in &self.side_channels {
+            let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+            outputs.push((StdioType::SideChannel(*child_fd), read));
+            child_fds.push((*child_fd, dup_above(&write, min_fd)?));
+        }
+
+        let child = if self.use_posix_spawn()? {
+            match posix_spawn(
+                &exec_args,
+                stdout.as_raw_fd(),
+                stderr.as_raw_fd(),
+                &child_fds,
+            ) {
+                Ok(child) => child,
+                Err(errno) => bail!(
+                    "Unable to spawn '{}': {}",
+                    self.command.to_string_lossy(),
+                    errno.desc()
+                ),
+            }
+        } else {
+            self.fork_exec(&mut exec_args, stdout, stderr, &child_fds, min_fd)?
+        };
+
+        // The slaves and the child's copies of any other fds belong to the child now
+        drop(slaves);
+        drop(child_fds);
+        drop(io);
+
+        // What the child writes to its ptys is recorded on its way to being streamed
+        let outputs = match &recorder {
+            Some(recorder) => outputs
+                .into_iter()
+                .map(|(stdio, fd)| match stdio {
+                    StdioType::SideChannel(_) => Ok((stdio, fd)),
+                    _ => Ok((stdio, tap(fd, recorder.clone())?)),
+                })
+                .collect::<Result<_>>()?,
+            None => outputs,
+        };
+
+        // Return a handle to the child, which takes ownership of the masters and side channels
+        let outputs = ChildOutputs {
+            fds: outputs,
+            terminal,
+            ptys,
+            drain_output: self.drain_output,
+            tees,
+            recorder,
+        };
+        XChildHandle::new(child, outputs, self.kill_on_drop, self.secrets.clone())
+    }
+
+    /// The child's values of the variables an asciinema recording keeps
+    fn recorded_env(&self) -> Vec<(&'static str, String)> {
+        ["SHELL", "TERM"]
+            .into_iter()
+            .filter_map(|key| {
+                let var = self
+                    .env
+                    .iter()
+                    .find(|var| var.key.as_bytes() == key.as_bytes())?;
+                let value = self
+                    .secrets
+                    .redact(&var.value.to_string_lossy())
+                    .into_owned();
+                Some((key, value))
+            })
+            .collect()
+    }
+
+    /// The highest fd number that something will be placed at in the child
+    fn max_child_fd(&self) -> RawFd {
+        let fds = self.fds.iter().map(|(child_fd, _)| *child_fd);
+        let side_channels = self.side_channels.iter().copied();
+        fds.chain(side_channels)
+            .fold(libc::STDERR_FILENO, RawFd::max)
+    }
+
+    /// The first option that posix_spawn() can't provide, if any
+    fn fork_only_option(&self) -> Option<&'static str> {
+        let options = [
+            (!self.pre_exec.is_empty(), "pre_exec"),
+            (self.parent_death_signal.is_some(), "parent_death_signal"),
+            (!self.rlimits.is_empty(), "rlimit"),
+            (self.nice.is_some(), "nice"),
+            (self.cpu_affinity.is_some(), "cpu_affinity"),
+            (self.io_priority.is_some(), "io_priority"),
+            (self.oom_score_adj.is_some(), "oom_score_adj"),
+            (self.uid.is_some(), "uid"),
+            (self.gid.is_some(), "gid"),
+            (self.groups.is_some(), "groups"),
+            // LISTEN_PID has to be set after fork()
+            (!self.listen_fd_names.is_empty(), "listen_fds"),
+            (self.pty_stdin, "pty_stdin"),
+        ];
+        options
+            .into_iter()
+            .find(|(set, _)| *set)
+            .map(|(_, name)| name)
+    }
+
+    fn use_posix_spawn(&self) -> Result<bool> {
+        match (self.backend, self.fork_only_option()) {
+            (SpawnBackend::Fork, _) => Ok(false),
+            (SpawnBackend::Auto, option) => Ok(option.is_none()),
+            (SpawnBackend::PosixSpawn, None) => Ok(true),
+            (SpawnBackend::PosixSpawn, Some(option)) => bail!(
+                "Unable to spawn '{}': '{}' is not supported by the posix_spawn backend",
+                self.command.to_string_lossy(),
+                option
+            ),
+        }
+    }
+
+    /// Fork and set up the child by hand, blocking until it has exec'ed
+    fn fork_exec(
+        &self,
+        exec_args: &mut ExecArgs,
+        stdout: &OwnedFd,
+        stderr: &OwnedFd,
+        fds: &[(RawFd, OwnedFd)],
+        min_fd: RawFd,
+    ) -> Result<Pid> {
+        // Remember who we are so the child can tell whether we died before it set up its
+        // parent-death signal
+        let parent = getpid();
+        let oom_score_adj = self.oom_score_adj.map(|adj| adj.to_string());
+
+        // Kept out of the way of the fds being placed in the child as well
+        let error_pipe = ErrorPipe::new(min_fd)?;
 
         let Ok(res) = (unsafe { fork() }) else {
             bail!("fork() failed");
@@ -131,39 +499,326 @@ impl XCommand {
 
         match res {
             ForkResult::Parent { child } => {
-                // We are the parent
-                close(stdout_slave).unwrap();
-                close(stderr_slave).unwrap();
-                // Return a handle to the child
-                Ok(XChildHandle::new(child, stdout_master, stderr_master).unwrap())
+                if let Some(error) = error_pipe.wait() {
+                    // The child has exited without exec'ing
+                    let _ = waitpid(child, None);
+                    bail!(self.describe_child_error(error));
+                }
+                Ok(child)
             }
             ForkResult::Child => {
-                // We are the child
-                close(stdout_master).unwrap();
-                close(stderr_master).unwrap();
-
-                /*
-                // TODO: what is this?
-                setsid()?;
-                let _ = unsafe { libc::ioctl(stdout_slave, libc::TIOCSCTTY, libc::STDOUT_FILENO) };
-                let _ = unsafe { libc::ioctl(stderr_slave, libc::TIOCSCTTY, libc::STDERR_FILENO) };
-                */
-
-                // Redirect the pty stdout/err to this process's stdout/err
-                dup2(stdout_slave.as_raw_fd(), libc::STDOUT_FILENO).unwrap();
-                dup2(stderr_slave.as_raw_fd(), libc::STDERR_FILENO).unwrap();
-
-                // TODO: pass through stdin
-
-                //Exec the command
-                let Err(e) = self.exec() else {
-                    unreachable!();
+                let setup = ChildSetup {
+                    exec_args,
+                    stdout,
+                    stderr,
+                    fds,
+                    error_fd: error_pipe.writer(),
+                    parent,
+                    oom_score_adj: oom_score_adj.as_deref(),
                 };
+                self.exec_child(setup)
+            }
+        }
+    }
 
-                error!("failed to exec: {}", e);
-                // TODO: set exit code based on error
-                std::process::exit(1);
+    /// Set up the child and exec. Runs between fork() and execve(), so see the safety rules
+    /// in 'spawn()'
+    fn exec_child(&self, setup: ChildSetup) -> ! {
+        let ChildSetup {
+            exec_args,
+            stdout,
+            stderr,
+            fds,
+            error_fd,
+            parent,
+            oom_score_adj,
+        } = setup;
+
+        // Start a new session, like the posix_spawn backend does with POSIX_SPAWN_SETSID
+        if let Err(errno) = setsid() {
+            report(error_fd, ChildStage::Session, errno, 0);
+        }
+
+        // Redirect the pty stdout/err to this process's stdout/err
+        if let Err(errno) = dup2(stdout.as_raw_fd(), libc::STDOUT_FILENO) {
+            report(error_fd, ChildStage::Stdio, errno, 1);
+        }
+        if let Err(errno) = dup2(stderr.as_raw_fd(), libc::STDERR_FILENO) {
+            report(error_fd, ChildStage::Stdio, errno, 2);
+        }
+        // dup2() clears close-on-exec on the new fd
+        for (child_fd, fd) in fds {
+            if let Err(errno) = dup2(fd.as_raw_fd(), *child_fd) {
+                report(error_fd, ChildStage::Stdio, errno, *child_fd as usize);
             }
         }
+
+        // A pty stdin becomes the controlling terminal of the new session, so that control
+        // characters sent to it signal the process and it can open /dev/tty
+        if self.pty_stdin {
+            let res = unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) };
+            if let Err(errno) = Errno::result(res) {
+                report(error_fd, ChildStage::ControllingTerminal, errno, 0);
+            }
+        }
+
+        if let Some(nice) = self.nice {
+            if let Err(errno) = set_nice(nice) {
+                report(error_fd, ChildStage::Nice, errno, 0);
+            }
+        }
+        if let Some(cpus) = &self.cpu_affinity {
+            if let Err(errno) = sched_setaffinity(Pid::from_raw(0), cpus) {
+                report(error_fd, ChildStage::CpuAffinity, errno, 0);
+            }
+        }
+        if let Some(priority) = self.io_priority {
+            if let Err(errno) = set_io_priority(priority) {
+                report(error_fd, ChildStage::IoPriority, errno, 0);
+            }
+        }
+        if let Some(adj) = oom_score_adj {
+            if let Err(errno) = set_oom_score_adj(adj.as_bytes()) {
+                report(error_fd, ChildStage::OomScoreAdj, errno, 0);
+            }
+        }
+
+        // Applied after the setup above so that e.g. a low RLIMIT_NOFILE can't get in its
+        // way, but before dropping privileges, which raising a hard limit may need
+        for (i, (resource, soft, hard)) in self.rlimits.iter().enumerate() {
+            if let Err(errno) = setrlimit(*resource, *soft, *hard) {
+                report(error_fd, ChildStage::ResourceLimit, errno, i);
+            }
+        }
+
+        // Supplementary groups and the gid can only be changed while we are still
+        // privileged, so the uid goes last. Like std's Command, root's own groups are dropped
+        // along with its uid or gid unless others were given, as they include gid 0
+        let changing_ids = self.uid.is_some() || self.gid.is_some();
+        let groups = match &self.groups {
+            Some(groups) => Some(&groups[..]),
+            None if changing_ids && Uid::effective().is_root() => Some(&[][..]),
+            None => None,
+        };
+        if let Some(groups) = groups {
+            if let Err(errno) = setgroups(groups) {
+                report(error_fd, ChildStage::Groups, errno, 0);
+            }
+        }
+        if let Some(gid) = self.gid {
+            if let Err(errno) = setgid(gid) {
+                report(error_fd, ChildStage::Gid, errno, 0);
+            }
+        }
+        if let Some(uid) = self.uid {
+            if let Err(errno) = setuid(uid) {
+                report(error_fd, ChildStage::Uid, errno, 0);
+            }
+        }
+
+        // Changing credentials clears the parent-death signal, so it has to come after
+        #[cfg(target_os = "linux")]
+        if let Some(signal) = self.parent_death_signal {
+            if let Err(errno) = nix::sys::prctl::set_pdeathsig(signal) {
+                report(error_fd, ChildStage::ParentDeathSignal, errno, 0);
+            }
+            // The parent may have died between fork() and prctl(), in which case we
+            // have already been reparented and the signal will never come
+            if getppid() != parent {
+                let _ = raise(signal);
+                unsafe { libc::_exit(1) };
+            }
+        }
+
+        for (i, hook) in self.pre_exec.iter().enumerate() {
+            if let Err(e) = (hook.0)() {
+                // Only the error code makes it back to the parent
+                let errno = Errno::from_raw(e.raw_os_error().unwrap_or(libc::EINVAL));
+                report(error_fd, ChildStage::PreExec, errno, i);
+            }
+        }
+
+        // Socket activated services check that the fds were meant for them
+        exec_args.fill_pid_var(getpid().as_raw());
+
+        //Exec the command
+        let errno = exec_args.exec();
+        report(error_fd, ChildStage::Exec, errno, 0);
+    }
+}
+
+/// Args and env as strings, with secrets masked
+impl fmt::Debug for XCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let redact = |value: &CString| self.secrets.redact(&value.to_string_lossy()).into_owned();
+        let args: Vec<String> = self.args.iter().map(redact).collect();
+        let env: Vec<(String, String)> = self
+            .env
+            .iter()
+            .map(|var| (var.key.to_string_lossy().into_owned(), redact(&var.value)))
+            .collect();
+        f.debug_struct("XCommand")
+            .field("command", &self.command.to_string_lossy())
+            .field("args", &args)
+            .field("env", &env)
+            .field("kill_on_drop", &self.kill_on_drop)
+            .field("parent_death_signal", &self.parent_death_signal)
+            .field("rlimits", &self.rlimits)
+            .field("nice", &self.nice)
+            .field("cpu_affinity", &self.cpu_affinity)
+            .field("io_priority", &self.io_priority)
+            .field("oom_score_adj", &self.oom_score_adj)
+            .field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("groups", &self.groups)
+            .field("pre_exec", &self.pre_exec)
+            .field("backend", &self.backend)
+            .field("fds", &self.fds)
+            .field("side_channels", &self.side_channels)
+            .field("listen_fd_names", &self.listen_fd_names)
+            .field("secrets", &self.secrets)
+            .field("pty_stdin", &self.pty_stdin)
+            .field("drain_output", &self.drain_output)
+            .field("tees", &self.tees)
+            .field("tee_options", &self.tee_options)
+            .field("window_size", &self.window_size)
+            .field("record", &self.record)
+            .finish()
+    }
+}
+
+/// The command as a shell command line, see 'to_shell_string()'
+impl fmt::Display for XCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_shell_string())
+    }
+}
+
+/// What the child needs to set itself up, prepared by the parent before fork()
+struct ChildSetup<'a, 'b> {
+    exec_args: &'a mut ExecArgs<'b>,
+    stdout: &'a OwnedFd,
+    stderr: &'a OwnedFd,
+    fds: &'a [(RawFd, OwnedFd)],
+    error_fd: RawFd,
+    parent: Pid,
+    oom_score_adj: Option<&'a str>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Odd words, each of which has to come back as it was
+    fn words() -> Vec<Vec<u8>> {
+        let words: [&[u8]; 18] = [
+            b"",
+            b"plain",
+            b" spaces around ",
+            b"it's",
+            b"''",
+            b"say \"hi\"",
+            b"back\\slash\\",
+            b"$HOME",
+            b"${HOME:-default}",
+            b"$'not ansi'",
+            b"line\nbreak\r\n",
+            b"tab\there",
+            b"*?[a-z]~#!&|;<>()`",
+            "caf\u{e9} \u{1f980}".as_bytes(),
+            b"\xff\xfe",
+            b"\x80'\\\x01\x7f",
+            b"caf\xc3",
+            b"-n",
+        ];
+        words.iter().map(|word| word.to_vec()).collect()
+    }
+
+    fn cstring(bytes: &[u8]) -> CString {
+        CString::new(bytes).unwrap()
+    }
+
+    fn round_trip(command: &XCommand) -> XCommand {
+        let line = command.to_shell_string();
+        match XCommand::parse(&line) {
+            Ok(builder) => builder.build(),
+            Err(e) => panic!("Unable to parse '{}': {}", line, e),
+        }
+    }
+
+    fn sorted_env(command: &XCommand) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut env: Vec<_> = command
+            .env
+            .iter()
+            .map(|var| (var.key.as_bytes().to_vec(), var.value.as_bytes().to_vec()))
+            .collect();
+        env.sort();
+        env
+    }
+
+    #[test]
+    fn args_round_trip() {
+        let mut command = XCommand::builder("/bin/echo").unwrap().build();
+        command.args = words().iter().map(|word| cstring(word)).collect();
+        let parsed = round_trip(&command);
+        assert_eq!(parsed.command, command.command);
+        assert_eq!(parsed.args, command.args);
+        assert_eq!(sorted_env(&parsed), sorted_env(&command));
+    }
+
+    #[test]
+    fn each_word_round_trips_on_its_own() {
+        for word in words() {
+            let mut command = XCommand::builder("/bin/echo").unwrap().build();
+            command.args = vec![cstring(&word)];
+            assert_eq!(round_trip(&command).args, command.args);
+        }
+    }
+
+    #[test]
+    fn changed_env_round_trips() {
+        let mut command = XCommand::builder("/usr/bin/env").unwrap().build();
+        for (i, word) in words().iter().enumerate() {
+            command.env.push(EnvVar {
+                key: cstring(format!("XCOMMAND_TEST_{}", i).as_bytes()),
+                value: cstring(word),
+            });
+        }
+        command.args = vec![cstring(b"-0")];
+        let line = command.to_shell_string();
+        assert!(line.starts_with("env XCOMMAND_TEST_0="), "{}", line);
+        let parsed = round_trip(&command);
+        assert_eq!(parsed.command, command.command);
+        assert_eq!(parsed.args, command.args);
+        assert_eq!(sorted_env(&parsed), sorted_env(&command));
+    }
+
+    #[test]
+    fn clean_env_round_trips() {
+        let mut command = XCommandBuilder::clean_environment("/usr/bin/env")
+            .var("EMPTY", "")
+            .unwrap()
+            .var("PATH", "/usr/bin:/bin")
+            .unwrap()
+            .build();
+        command.env.push(EnvVar {
+            key: cstring(b"BYTES"),
+            value: cstring(b"\xff'\n"),
+        });
+        let line = command.to_shell_string();
+        assert!(line.starts_with("env -i "), "{}", line);
+        let parsed = round_trip(&command);
+        assert_eq!(parsed.command, command.command);
+        assert_eq!(parsed.args, command.args);
+        assert_eq!(sorted_env(&parsed), sorted_env(&command));
+    }
+
+    #[test]
+    fn env_without_a_command_is_the_command() {
+        let parsed = XCommand::parse("/usr/bin/env -i FOO=bar").unwrap().build();
+        assert_eq!(parsed.command, cstring(b"/usr/bin/env"));
+        let parsed = XCommand::parse("env -i FOO=bar").unwrap().build();
+        assert_eq!(parsed.args, [cstring(b"-i"), cstring(b"FOO=bar")]);
     }
 }
@@ -0,0 +1,352 @@
+use crate::read::{read_chunks, LineSplitter};
+use crate::tee::{TeeFile, Tees};
+use crate::StdioType;
+use async_stream::stream;
+use eyre::Result;
+use nix::unistd::{mkstemp, unlink};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use tokio::sync::Notify;
+use tokio_stream::Stream;
+
+/// What to do with a line of output when the drain buffer it belongs in is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrainPolicy {
+    /// Stop reading until there's room, so the child blocks once its pty fills up as well. Once
+    /// the XChildHandle and XStreamer are dropped, lines are discarded instead
+    #[default]
+    Block,
+    /// Make room by dropping the oldest lines
+    DropOldest,
+    /// Drop the line
+    DropNewest,
+    /// Write the line to an unlinked temp file, from which it is read back in order
+    Spill,
+}
+
+/// What the drain buffers of a child had to do to keep up, totalled across its outputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct XDrainStats {
+    pub dropped_lines: u64,
+    pub dropped_bytes: u64,
+    pub spilled_lines: u64,
+    pub spilled_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct DrainCounters {
+    dropped_lines: AtomicU64,
+    dropped_bytes: AtomicU64,
+    spilled_lines: AtomicU64,
+    spilled_bytes: AtomicU64,
+}
+
+impl DrainCounters {
+    pub fn stats(&self) -> XDrainStats {
+        XDrainStats {
+            dropped_lines: self.dropped_lines.load(Ordering::Relaxed),
+            dropped_bytes: self.dropped_bytes.load(Ordering::Relaxed),
+            spilled_lines: self.spilled_lines.load(Ordering::Relaxed),
+            spilled_bytes: self.spilled_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn dropped(&self, line: &str) {
+        self.dropped_lines.fetch_add(1, Ordering::Relaxed);
+        self.dropped_bytes
+            .fetch_add(line.len() as u64, Ordering::Relaxed);
+    }
+
+    fn spilled(&self, line: &str) {
+        self.spilled_lines.fetch_add(1, Ordering::Relaxed);
+        self.spilled_bytes
+            .fetch_add(line.len() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Lines that didn't fit in memory, one per line of the file
+#[derive(Debug)]
+struct Spill {
+    file: File,
+    read: u64,
+    written: u64,
+    /// Lines written but not read back yet
+    pending: usize,
+}
+
+impl Spill {
+    fn create() -> io::Result<Self> {
+        let (fd, path) = mkstemp(&std::env::temp_dir().join("xcommand-spill-XXXXXX"))?;
+        // mkstemp() hands over a new fd that nothing else owns
+        let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        unlink(&path)?;
+        Ok(Self {
+            file,
+            read: 0,
+            written: 0,
+            pending: 0,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        self.file.write_all_at(&bytes, self.written)?;
+        self.written += bytes.len() as u64;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn read(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let n = self
+                .file
+                .read_at(&mut chunk, self.read + line.len() as u64)?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if let Some(end) = chunk[..n].iter().position(|&byte| byte == b'\n') {
+                line.extend_from_slice(&chunk[..end]);
+                break;
+            }
+            line.extend_from_slice(&chunk[..n]);
+        }
+        self.read += line.len() as u64 + 1;
+        self.pending -= 1;
+        if self.pending == 0 {
+            // Everything has been read back, so start over rather than let the file grow
+            self.file.set_len(0)?;
+            self.read = 0;
+            self.written = 0;
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffered {
+    lines: VecDeque<String>,
+    bytes: usize,
+    spill: Option<Spill>,
+    /// Set once the output has been read to the end
+    closed: bool,
+    /// Set once nothing is left to take lines out, after which they are discarded
+    abandoned: bool,
+}
+
+enum Next {
+    Line(String),
+    Empty,
+    Closed,
+}
+
+/// Output of one of the child's fds, read on a thread of its own into a bounded buffer
+#[derive(Debug)]
+pub(crate) struct DrainBuffer {
+    policy: DrainPolicy,
+    /// In bytes of output. A line bigger than this is still let in when the buffer is empty
+    capacity: usize,
+    state: Mutex<Buffered>,
+    /// Wakes the reader when a line is taken out, for DrainPolicy::Block
+    room: Condvar,
+    /// Wakes the streamer when a line is put in or the output ends
+    ready: Notify,
+    counters: Arc<DrainCounters>,
+}
+
+impl DrainBuffer {
+    /// Start draining 'fd' on a thread of its own, writing each line to 'tees' as it is read
+    pub fn start(
+        stdio: StdioType,
+        fd: OwnedFd,
+        tees: Vec<Arc<Mutex<TeeFile>>>,
+        policy: DrainPolicy,
+        capacity: usize,
+        counters: Arc<DrainCounters>,
+    ) -> Result<Arc<Self>> {
+        let buffer = Arc::new(Self {
+            policy,
+            capacity,
+            state: Mutex::new(Buffered::default()),
+            room: Condvar::new(),
+            ready: Notify::new(),
+            counters,
+        });
+        let drain = buffer.clone();
+        thread::Builder::new()
+            .name("xcommand-drain".to_string())
+            .spawn(move || {
+                let keep = |line: String| {
+                    for tee in &tees {
+                        tee.lock().unwrap().write_line(stdio, &line);
+                    }
+                    drain.push(line);
+                };
+                let mut lines = LineSplitter::default();
+                read_chunks(File::from(fd), |chunk| {
+                    lines.push(chunk, keep);
+                    true
+                });
+                if let Some(line) = lines.finish() {
+                    keep(line);
+                }
+                drain.state.lock().unwrap().closed = true;
+                drain.ready.notify_one();
+            })?;
+        Ok(buffer)
+    }
+
+    fn push(&self, line: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.abandoned {
+            return;
+        }
+        let full =
+            |state: &Buffered| !state.lines.is_empty() && state.bytes + line.len() > self.capacity;
+        match self.policy {
+            DrainPolicy::Block => {
+                while full(&state) && !state.abandoned {
+                    state = self.room.wait(state).unwrap();
+                }
+                if state.abandoned {
+                    return;
+                }
+            }
+            DrainPolicy::DropOldest => {
+                while full(&state) {
+                    if let Some(oldest) = state.lines.pop_front() {
+                        state.bytes -= oldest.len();
+                        self.counters.dropped(&oldest);
+                    }
+                }
+            }
+            DrainPolicy::DropNewest => {
+                if full(&state) {
+                    self.counters.dropped(&line);
+                    return;
+                }
+            }
+            DrainPolicy::Spill => {
+                // Once lines are spilled, later ones follow them until they've all been read back
+                let spilling = state.spill.as_ref().is_some_and(|spill| spill.pending > 0);
+                if spilling || full(&state) {
+                    if state.spill.is_none() {
+                        state.spill = Spill::create().ok();
+                    }
+                    let written = match &mut state.spill {
+                        Some(spill) => spill.write(&line).is_ok(),
+                        None => false,
+                    };
+                    if written {
+                        self.counters.spilled(&line);
+                    } else {
+                        self.counters.dropped(&line);
+                    }
+                    self.ready.notify_one();
+                    return;
+                }
+            }
+        }
+        state.bytes += line.len();
+        state.lines.push_back(line);
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    fn next(&self) -> Next {
+        let mut state = self.state.lock().unwrap();
+        if let Some(line) = state.lines.pop_front() {
+            state.bytes -= line.len();
+            self.room.notify_one();
+            return Next::Line(line);
+        }
+        // Spilled lines are all newer than the ones in memory
+        if let Some(spill) = &mut state.spill {
+            if spill.pending > 0 {
+                match spill.read() {
+                    Ok(line) => return Next::Line(line),
+                    // The rest of the spilled lines are lost
+                    Err(_) => spill.pending = 0,
+                }
+            }
+        }
+        if state.closed {
+            Next::Closed
+        } else {
+            Next::Empty
+        }
+    }
+
+    /// Let go of the buffered lines and stop keeping new ones, waking the reader if it is
+    /// waiting for room
+    fn abandon(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.abandoned = true;
+        state.lines.clear();
+        state.bytes = 0;
+        state.spill = None;
+        drop(state);
+        self.room.notify_all();
+    }
+
+    /// The buffered lines, as they come. Only one stream should be taken from a buffer
+    fn stream(self: Arc<Self>) -> impl Stream<Item = String> + Send {
+        stream! {
+            loop {
+                match self.next() {
+                    Next::Line(line) => yield line,
+                    Next::Closed => return,
+                    // The permit left by notify_one() means a line pushed in the meantime isn't
+                    // missed
+                    Next::Empty => self.ready.notified().await,
+                }
+            }
+        }
+    }
+}
+
+/// The consuming end of a DrainBuffer, held by the XChildHandle and then the XStreamer. Once
+/// it is dropped the buffer is abandoned, so that the reader never waits on it for room and
+/// reads on to the end of the output, closing it
+#[derive(Debug)]
+pub(crate) struct Drained(Arc<DrainBuffer>);
+
+impl Drained {
+    /// See 'DrainBuffer::stream()'
+    pub fn stream(&self) -> impl Stream<Item = String> + Send {
+        self.0.clone().stream()
+    }
+}
+
+impl Drop for Drained {
+    fn drop(&mut self) {
+        self.0.abandon();
+    }
+}
+
+/// Start draining each of 'outputs'
+pub(crate) fn drain(
+    outputs: Vec<(StdioType, OwnedFd)>,
+    tees: &Tees,
+    policy: DrainPolicy,
+    capacity: usize,
+    counters: &Arc<DrainCounters>,
+) -> Result<Vec<(StdioType, Drained)>> {
+    outputs
+        .into_iter()
+        .map(|(stdio, fd)| {
+            let files = tees.files_for(stdio);
+            let buffer = DrainBuffer::start(stdio, fd, files, policy, capacity, counters.clone())?;
+            Ok((stdio, Drained(buffer)))
+        })
+        .collect()
+}
@@ -0,0 +1,116 @@
+use crate::env_var::EnvVar;
+use nix::errno::Errno;
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+
+/// Everything execve() needs, laid out before fork() so that the child doesn't have to
+/// allocate. Allocating after fork() in a multithreaded process can deadlock on a lock that
+/// another thread held at the time of the fork
+pub(crate) struct ExecArgs<'a> {
+    path: &'a CStr,
+    /// Null terminated, pointing into the XCommand's args
+    argv: Vec<*const c_char>,
+    /// Null terminated, pointing into 'env'
+    envp: Vec<*const c_char>,
+    /// The 'key=value' strings envp points into
+    _env: Vec<CString>,
+    /// 'key=' followed by room for the digits of a variable whose value is the child's own
+    /// pid, which is only known after fork(). Boxed so that envp's pointer to it stays put
+    pid_var: Option<(Box<[u8; PID_VAR_LEN]>, usize)>,
+}
+
+const PID_VAR_LEN: usize = 64;
+
+impl<'a> ExecArgs<'a> {
+    /// 'pid_var' names a variable to set to the child's pid with 'fill_pid_var()'
+    pub fn new(
+        command: &'a CString,
+        args: &'a [CString],
+        env: &[EnvVar],
+        pid_var: Option<&str>,
+    ) -> Self {
+        // The command name is argv[0]
+        let mut argv = Vec::with_capacity(args.len() + 2);
+        argv.push(command.as_ptr());
+        argv.extend(args.iter().map(|arg| arg.as_ptr()));
+        argv.push(ptr::null());
+
+        let env: Vec<CString> = env.iter().map(format_var).collect();
+        let mut envp = Vec::with_capacity(env.len() + 2);
+        envp.extend(env.iter().map(|var| var.as_ptr()));
+
+        let pid_var = pid_var.map(|key| {
+            // Room for the longest pid and the nul
+            assert!(key.len() + 1 + 20 < PID_VAR_LEN);
+            let mut buf = Box::new([0u8; PID_VAR_LEN]);
+            buf[..key.len()].copy_from_slice(key.as_bytes());
+            buf[key.len()] = b'=';
+            envp.push(buf.as_ptr() as *const c_char);
+            (buf, key.len() + 1)
+        });
+        envp.push(ptr::null());
+
+        Self {
+            path: command,
+            argv,
+            envp,
+            _env: env,
+            pid_var,
+        }
+    }
+
+    /// Set the pid variable's value. Doesn't allocate, so it is safe to call between fork() and
+    /// execve()
+    pub fn fill_pid_var(&mut self, pid: i32) {
+        let Some((buf, start)) = &mut self.pid_var else {
+            return;
+        };
+        let mut digits = [0u8; 20];
+        let mut len = 0;
+        let mut rest = pid.unsigned_abs();
+        loop {
+            digits[len] = b'0' + (rest % 10) as u8;
+            len += 1;
+            rest /= 10;
+            if rest == 0 {
+                break;
+            }
+        }
+        for (i, digit) in digits[..len].iter().rev().enumerate() {
+            buf[*start + i] = *digit;
+        }
+        buf[*start + len] = 0;
+    }
+
+    pub fn path(&self) -> *const c_char {
+        self.path.as_ptr()
+    }
+
+    /// In the form posix_spawn() takes it
+    pub fn argv(&self) -> *const *mut c_char {
+        self.argv.as_ptr() as *const *mut c_char
+    }
+
+    /// In the form posix_spawn() takes it
+    pub fn envp(&self) -> *const *mut c_char {
+        self.envp.as_ptr() as *const *mut c_char
+    }
+
+    /// Replace the current process. Only returns if execve() failed
+    pub fn exec(&self) -> Errno {
+        unsafe { libc::execve(self.path.as_ptr(), self.argv.as_ptr(), self.envp.as_ptr()) };
+        Errno::last()
+    }
+}
+
+/// Format a variable as 'key=value'
+fn format_var(var: &EnvVar) -> CString {
+    let key = var.key.as_bytes();
+    let value = var.value.as_bytes();
+    let mut formatted = Vec::with_capacity(key.len() + value.len() + 2);
+    formatted.extend_from_slice(key);
+    formatted.push(b'=');
+    formatted.extend_from_slice(value);
+    // Neither half contains a nul, they are CStrings already
+    CString::new(formatted).unwrap()
+}
@@ -0,0 +1,125 @@
+use crate::XStatus;
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitid, Id, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Final status of a child process along with what it cost to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XExit {
+    pub status: XStatus,
+    /// When the process was spawned
+    pub started: SystemTime,
+    /// When the process was reaped
+    pub ended: SystemTime,
+    /// Wall clock time between spawning and reaping the process
+    pub wall_time: Duration,
+    /// CPU time spent in user mode
+    pub user_time: Duration,
+    /// CPU time spent in kernel mode
+    pub system_time: Duration,
+    /// Peak resident set size in bytes
+    pub max_rss: u64,
+    /// Page faults serviced without any I/O
+    pub minor_faults: u64,
+    /// Page faults that required I/O
+    pub major_faults: u64,
+    /// Times the process gave up the CPU, usually to wait on a resource
+    pub voluntary_context_switches: u64,
+    /// Times the process was preempted
+    pub involuntary_context_switches: u64,
+}
+
+impl XExit {
+    pub(crate) fn new(
+        status: XStatus,
+        started: SystemTime,
+        started_instant: Instant,
+        usage: &libc::rusage,
+    ) -> Self {
+        let wall_time = started_instant.elapsed();
+        XExit {
+            status,
+            started,
+            ended: started + wall_time,
+            wall_time,
+            user_time: timeval_to_duration(usage.ru_utime),
+            system_time: timeval_to_duration(usage.ru_stime),
+            // Linux reports this in KiB
+            max_rss: usage.ru_maxrss as u64 * 1024,
+            minor_faults: usage.ru_minflt as u64,
+            major_faults: usage.ru_majflt as u64,
+            voluntary_context_switches: usage.ru_nvcsw as u64,
+            involuntary_context_switches: usage.ru_nivcsw as u64,
+        }
+    }
+}
+
+fn timeval_to_duration(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+/// waitpid() that also returns the resource usage of the child.
+/// The usage is only meaningful once the child has terminated
+pub(crate) fn wait4(pid: Pid, flags: WaitPidFlag) -> nix::Result<(WaitStatus, libc::rusage)> {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    let res = unsafe { libc::wait4(pid.as_raw(), &mut status, flags.bits(), &mut usage) };
+    let pid = Pid::from_raw(Errno::result(res)?);
+    let status = if pid.as_raw() == 0 {
+        WaitStatus::StillAlive
+    } else {
+        WaitStatus::from_raw(pid, status)?
+    };
+    Ok((status, usage))
+}
+
+/// Whether a child has been reaped, after which its pid may belong to an unrelated process.
+/// Reaping and signalling the child both hold the lock, so that a signal can't slip in between
+/// the reap and the flag being set and reach a recycled pid
+#[derive(Debug, Default)]
+pub(crate) struct Reaped(Mutex<bool>);
+
+impl Reaped {
+    pub fn get(&self) -> bool {
+        *self.0.lock().unwrap()
+    }
+
+    /// Send 'signal' to 'pid' unless it has been reaped, returning whether it was sent
+    pub fn kill(&self, pid: Pid, signal: Signal) -> nix::Result<bool> {
+        let reaped = self.0.lock().unwrap();
+        if *reaped {
+            return Ok(false);
+        }
+        kill(pid, signal)?;
+        Ok(true)
+    }
+
+    /// Block until 'pid' changes state, then 'wait4()' it with 'flags', marking it reaped if it
+    /// has terminated. The state change is waited for without reaping, so that the child is
+    /// only reaped once the lock is held
+    pub fn wait4(&self, pid: Pid, flags: WaitPidFlag) -> nix::Result<(WaitStatus, libc::rusage)> {
+        let mut peek = WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT;
+        if flags.contains(WaitPidFlag::WUNTRACED) {
+            peek |= WaitPidFlag::WSTOPPED;
+        }
+        if flags.contains(WaitPidFlag::WCONTINUED) {
+            peek |= WaitPidFlag::WCONTINUED;
+        }
+        loop {
+            waitid(Id::Pid(pid), peek)?;
+            let mut reaped = self.0.lock().unwrap();
+            let (status, usage) = wait4(pid, flags | WaitPidFlag::WNOHANG)?;
+            match status {
+                // Nothing to collect after all, e.g. a stop that was followed by a continue
+                WaitStatus::StillAlive => continue,
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => *reaped = true,
+                _ => {}
+            }
+            return Ok((status, usage));
+        }
+    }
+}
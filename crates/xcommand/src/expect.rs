@@ -0,0 +1,406 @@
+use crate::child_handle::Waiter;
+use crate::read::LineSplitter;
+use crate::ready::OutputLog;
+use crate::record::Recorder;
+use crate::secret::Secrets;
+use crate::StdioType;
+use crate::XStatus;
+use eyre::bail;
+use eyre::Result;
+use nix::unistd::Pid;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_fd::AsyncFd;
+
+/// Default for 'XExpect::set_buffer_limit()'
+const BUFFER_LIMIT: usize = 64 * 1024;
+
+/// Chunks read but not received yet, of up to 4 KiB each. Past this the readers wait, so the
+/// child blocks on writing to its pty as well
+const QUEUED_CHUNKS: usize = 16;
+
+/// What to wait for in the child's output
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// The byte range of the first match in 'text', and its capture groups
+    pub(crate) fn find(&self, text: &str) -> Option<(Range<usize>, Vec<Option<String>>)> {
+        match self {
+            Self::Literal(literal) => text
+                .find(literal.as_str())
+                .map(|start| (start..start + literal.len(), Vec::new())),
+            Self::Regex(regex) => regex.captures(text).map(|captures| {
+                let groups = captures
+                    .iter()
+                    .skip(1)
+                    .map(|group| group.map(|group| group.as_str().to_string()))
+                    .collect();
+                // Group 0 is the whole match, which is always there
+                (captures.get(0).unwrap().range(), groups)
+            }),
+        }
+    }
+}
+
+impl From<&str> for Pattern {
+    fn from(literal: &str) -> Self {
+        Self::Literal(literal.to_string())
+    }
+}
+
+impl From<String> for Pattern {
+    fn from(literal: String) -> Self {
+        Self::Literal(literal)
+    }
+}
+
+impl From<Regex> for Pattern {
+    fn from(regex: Regex) -> Self {
+        Self::Regex(regex)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Literal(literal) => write!(f, "{:?}", literal),
+            Self::Regex(regex) => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+/// A match of a Pattern, and the output that came before it since the last match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XMatch {
+    pub before: String,
+    pub matched: String,
+    /// The regex's capture groups, if it had any
+    pub groups: Vec<Option<String>>,
+}
+
+/// One step of an expect session, for debugging
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranscriptEntry {
+    Received {
+        elapsed: Duration,
+        stdio: StdioType,
+        text: String,
+    },
+    Sent {
+        elapsed: Duration,
+        text: String,
+    },
+}
+
+impl fmt::Display for TranscriptEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Received {
+                elapsed,
+                stdio,
+                text,
+            } => write!(f, "[{:>9.3?}] <- {:?} {:?}", elapsed, stdio, text),
+            Self::Sent { elapsed, text } => write!(f, "[{:>9.3?}] -> {:?}", elapsed, text),
+        }
+    }
+}
+
+/// Drives an interactive child: waits for patterns in everything it outputs (stdout, stderr,
+/// side channels and its terminal, merged in the order it arrives) and sends it input.
+/// Output that hasn't been matched yet is kept in a rolling buffer, so prompts without a
+/// trailing newline can be matched. Pty output has '\r\n' line endings. Output is only read
+/// ahead of 'expect()' by about as much as the buffer holds, after which the child blocks on
+/// writing, as it would on a terminal nobody reads
+pub struct XExpect {
+    pid: Pid,
+    chunks: mpsc::Receiver<(StdioType, Vec<u8>)>,
+    /// Incomplete UTF-8 sequences at the end of each output's last chunk
+    partial: HashMap<StdioType, Vec<u8>>,
+    /// Output that hasn't been matched yet
+    buffer: String,
+    buffer_limit: usize,
+    /// Set once every output has been closed
+    eof: bool,
+    /// Writes to the master of the child's pty stdin, which 'terminal' owns
+    writer: Option<AsyncFd>,
+    terminal: Option<OwnedFd>,
+    transcript: Vec<TranscriptEntry>,
+    /// Records what is sent, see 'XCommandBuilder::record()'
+    recorder: Option<Arc<Recorder>>,
+    /// Masked in errors and Debug output
+    secrets: Secrets,
+    started: Instant,
+    _waiter: JoinHandle<nix::Result<XStatus>>,
+}
+
+impl XExpect {
+    pub(crate) fn new(
+        pid: Pid,
+        outputs: Vec<(StdioType, OwnedFd)>,
+        terminal: Option<OwnedFd>,
+        waiter: Waiter,
+        recorder: Option<Arc<Recorder>>,
+        log: Arc<OutputLog>,
+        secrets: Secrets,
+    ) -> Result<Self> {
+        let (tx, chunks) = mpsc::channel(QUEUED_CHUNKS);
+        let readers: Vec<_> = outputs
+            .into_iter()
+            .map(|(stdio, fd)| tokio::spawn(read_output(stdio, fd, tx.clone(), log.clone())))
+            .collect();
+        // So that readiness checks know when they've seen all of a dead child's output
+        tokio::spawn(async move {
+            for reader in readers {
+                let _ = reader.await;
+            }
+            log.close();
+        });
+        let writer = match &terminal {
+            Some(terminal) => Some(AsyncFd::try_from(terminal.as_raw_fd())?),
+            None => None,
+        };
+        Ok(Self {
+            pid,
+            chunks,
+            partial: HashMap::new(),
+            buffer: String::new(),
+            buffer_limit: BUFFER_LIMIT,
+            eof: false,
+            writer,
+            terminal,
+            transcript: Vec::new(),
+            recorder,
+            secrets,
+            started: Instant::now(),
+            _waiter: waiter.spawn(),
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Wait up to 'timeout' for 'pattern', a literal string or a regex::Regex, to show up in
+    /// the output. The buffer is consumed up to the end of the match
+    pub async fn expect(
+        &mut self,
+        pattern: impl Into<Pattern>,
+        timeout: Duration,
+    ) -> Result<XMatch> {
+        let pattern = pattern.into();
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some((range, groups)) = pattern.find(&self.buffer) {
+                let before = self.buffer[..range.start].to_string();
+                let matched = self.buffer[range.clone()].to_string();
+                self.buffer.drain(..range.end);
+                return Ok(XMatch {
+                    before,
+                    matched,
+                    groups,
+                });
+            }
+            if self.eof {
+                bail!(
+                    "Process {} closed its output before {} was seen. Unmatched output: {:?}",
+                    self.pid,
+                    pattern,
+                    self.secrets.redact(&self.buffer)
+                );
+            }
+            if !self.receive_until(deadline).await {
+                bail!(
+                    "Timed out after {:?} waiting for {} from process {}. Unmatched output: {:?}",
+                    timeout,
+                    pattern,
+                    self.pid,
+                    self.secrets.redact(&self.buffer)
+                );
+            }
+        }
+    }
+
+    /// Wait up to 'timeout' for the child (and anything else holding its ptys) to close its
+    /// output, returning what it output since the last match
+    pub async fn expect_eof(&mut self, timeout: Duration) -> Result<String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.eof {
+            if !self.receive_until(deadline).await {
+                bail!(
+                    "Timed out after {:?} waiting for process {} to close its output. Unmatched output: {:?}",
+                    timeout,
+                    self.pid,
+                    self.secrets.redact(&self.buffer)
+                );
+            }
+        }
+        Ok(std::mem::take(&mut self.buffer))
+    }
+
+    /// Type 'text' into the child's terminal
+    pub async fn send(&mut self, text: &str) -> Result<()> {
+        let Some(writer) = &mut self.writer else {
+            bail!(
+                "Process {} has no terminal to send to, see 'XCommandBuilder::pty_stdin()'",
+                self.pid
+            );
+        };
+        // Before it's written, so that it isn't recorded after the child's response to it
+        if let Some(recorder) = &self.recorder {
+            recorder.input(text);
+        }
+        writer.write_all(text.as_bytes()).await?;
+        self.transcript.push(TranscriptEntry::Sent {
+            elapsed: self.started.elapsed(),
+            text: text.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Type 'line' followed by the Enter key, which sends a carriage return
+    pub async fn send_line(&mut self, line: &str) -> Result<()> {
+        self.send(&format!("{}\r", line)).await
+    }
+
+    /// Type the control character for 'key', e.g. 'c' for Ctrl-C, which has the terminal send
+    /// SIGINT to the child, or 'd' for end of input
+    pub async fn send_control(&mut self, key: char) -> Result<()> {
+        let code = match key.to_ascii_uppercase() {
+            key @ '@'..='_' => key as u8 & 0x1f,
+            '?' => 0x7f,
+            _ => bail!("There is no control character for '{}'", key),
+        };
+        self.send(&char::from(code).to_string()).await
+    }
+
+    /// Output that hasn't been matched yet
+    pub fn buffer(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Limit how much unmatched output is kept, in bytes (default 64 KiB). The oldest output is
+    /// dropped first, so a match that would have started in it is missed
+    pub fn set_buffer_limit(&mut self, limit: usize) {
+        self.buffer_limit = limit;
+        self.trim_buffer();
+    }
+
+    /// Everything sent and received so far, in order
+    pub fn transcript(&self) -> &[TranscriptEntry] {
+        &self.transcript
+    }
+
+    /// Wait for a chunk of output until 'deadline'. Returns false if the deadline passed
+    async fn receive_until(&mut self, deadline: tokio::time::Instant) -> bool {
+        match tokio::time::timeout_at(deadline, self.chunks.recv()).await {
+            Ok(Some((stdio, bytes))) => self.receive(stdio, bytes),
+            Ok(None) => self.eof = true,
+            Err(_) => return false,
+        }
+        true
+    }
+
+    fn receive(&mut self, stdio: StdioType, bytes: Vec<u8>) {
+        let partial = self.partial.entry(stdio).or_default();
+        partial.extend_from_slice(&bytes);
+        let text = decode(partial);
+        if text.is_empty() {
+            return;
+        }
+        self.buffer.push_str(&text);
+        self.trim_buffer();
+        self.transcript.push(TranscriptEntry::Received {
+            elapsed: self.started.elapsed(),
+            stdio,
+            text,
+        });
+    }
+
+    fn trim_buffer(&mut self) {
+        if self.buffer.len() <= self.buffer_limit {
+            return;
+        }
+        let mut start = self.buffer.len() - self.buffer_limit;
+        while !self.buffer.is_char_boundary(start) {
+            start += 1;
+        }
+        self.buffer.drain(..start);
+    }
+}
+
+impl fmt::Debug for XExpect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("XExpect")
+            .field("pid", &self.pid)
+            .field("buffer", &self.secrets.redact(&self.buffer))
+            .field("eof", &self.eof)
+            .field("terminal", &self.terminal)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for XExpect {
+    fn drop(&mut self) {
+        // The writer only borrows the terminal's fd, so it has to go first
+        self.writer.take();
+        self.terminal.take();
+    }
+}
+
+/// Take the text out of 'bytes', leaving an incomplete UTF-8 sequence at the end for the next
+/// chunk to complete. Invalid bytes are replaced
+pub(crate) fn decode(bytes: &mut Vec<u8>) -> String {
+    let complete = match std::str::from_utf8(bytes) {
+        Ok(_) => bytes.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => bytes.len(),
+    };
+    let text = String::from_utf8_lossy(&bytes[..complete]).into_owned();
+    bytes.drain(..complete);
+    text
+}
+
+/// Send chunks of an output as they are read, until it is closed, waiting while too many are
+/// queued. Each line is also pushed to 'log' as it is read, for
+/// 'XChildHandle::wait_until_output()'
+async fn read_output(
+    stdio: StdioType,
+    fd: OwnedFd,
+    tx: mpsc::Sender<(StdioType, Vec<u8>)>,
+    log: Arc<OutputLog>,
+) {
+    // The AsyncFd only borrows the descriptor, which is closed after it is dropped
+    let Ok(mut reader) = AsyncFd::try_from(fd.as_raw_fd()) else {
+        return;
+    };
+    let mut buf = vec![0u8; 4096];
+    let mut lines = LineSplitter::default();
+    // Like 'read_chunks()', which this can't use as it reads asynchronously
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                lines.push(&buf[..n], |line| log.push(stdio, &line));
+                // Nothing is expecting any more, but the log is still kept
+                let _ = tx.send((stdio, buf[..n].to_vec())).await;
+            }
+        }
+    }
+    if let Some(line) = lines.finish() {
+        log.push(stdio, &line);
+    }
+    drop(reader);
+    drop(fd);
+}
@@ -0,0 +1,136 @@
+use crate::StdioType;
+use crate::XStreamer;
+use async_stream::stream;
+use eyre::Result;
+use nix::unistd::Pid;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::{Stream, StreamExt};
+
+/// How many lines a subscriber can fall behind before it starts missing them
+const BACKLOG: usize = 1024;
+
+/// A line of output, or the error that ended the stream. Errors are kept as text so that they
+/// can be sent to every subscriber
+type Item = std::result::Result<(StdioType, String), String>;
+
+/// How much recent output an XHub replays to a new subscriber
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Replay {
+    #[default]
+    None,
+    /// The last N lines
+    Lines(usize),
+    /// As many of the last lines as fit in N bytes
+    Bytes(usize),
+}
+
+#[derive(Debug)]
+struct Shared {
+    replay: VecDeque<Item>,
+    /// Bytes of output in 'replay'
+    replay_bytes: usize,
+    /// Dropped once the output has ended, which ends every subscription
+    tx: Option<broadcast::Sender<Item>>,
+}
+
+impl Shared {
+    fn keep(&mut self, item: &Item, limit: Replay) {
+        if let Ok((_, line)) = item {
+            self.replay_bytes += line.len();
+        }
+        self.replay.push_back(item.clone());
+        loop {
+            let over = match limit {
+                Replay::None => !self.replay.is_empty(),
+                Replay::Lines(lines) => self.replay.len() > lines,
+                Replay::Bytes(bytes) => self.replay_bytes > bytes,
+            };
+            if !over {
+                return;
+            }
+            if let Some(Ok((_, line))) = self.replay.pop_front() {
+                self.replay_bytes -= line.len();
+            }
+        }
+    }
+}
+
+/// Fans the output of a child out to any number of subscribers. A single task reads the output,
+/// so it keeps flowing however many subscribers there are, or however slow they are: a
+/// subscriber that falls more than 1024 lines behind misses lines rather than holding up the
+/// child. Cloning the hub is cheap, and every clone subscribes to the same output
+#[derive(Debug, Clone)]
+pub struct XHub {
+    pid: Pid,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl XHub {
+    /// Start reading the output of 'streamer' on a task of its own. Must be called within a
+    /// tokio runtime
+    pub(crate) fn new(pid: Pid, mut streamer: XStreamer, replay: Replay) -> Self {
+        let (tx, _) = broadcast::channel(BACKLOG);
+        let shared = Arc::new(Mutex::new(Shared {
+            replay: VecDeque::new(),
+            replay_bytes: 0,
+            tx: Some(tx),
+        }));
+
+        let reader = shared.clone();
+        tokio::spawn(async move {
+            let mut stream = streamer.stream();
+            while let Some(item) = stream.next().await {
+                let item = item.map_err(|e| format!("{:#}", e));
+                let mut shared = reader.lock().unwrap();
+                shared.keep(&item, replay);
+                if let Some(tx) = &shared.tx {
+                    // Having no subscribers right now isn't an error
+                    let _ = tx.send(item);
+                }
+            }
+            reader.lock().unwrap().tx.take();
+        });
+
+        Self { pid, shared }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Lines of output, starting with the replay of recent ones, as 'XStreamer::stream()'
+    /// yields them. If the subscriber fell behind, an error says how many lines it missed and
+    /// the stream carries on. Ends when the child's output does
+    pub fn subscribe(&self) -> impl Stream<Item = Result<(StdioType, String)>> {
+        // Taking both under the lock means no line is missed or seen twice
+        let (replay, rx) = {
+            let shared = self.shared.lock().unwrap();
+            let rx = shared.tx.as_ref().map(broadcast::Sender::subscribe);
+            (shared.replay.clone(), rx)
+        };
+        let pid = self.pid;
+        stream! {
+            for item in replay {
+                yield item.map_err(|e| eyre::eyre!(e));
+            }
+            let Some(mut rx) = rx else {
+                return;
+            };
+            loop {
+                match rx.recv().await {
+                    Ok(item) => yield item.map_err(|e| eyre::eyre!(e)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        yield Err(eyre::eyre!(
+                            "Fell behind the output of process {} and missed {} lines",
+                            pid,
+                            missed
+                        ));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        }
+    }
+}
@@ -1,61 +1,36 @@
-use async_stream::stream;
-use eyre::bail;
-use eyre::Result;
-use futures::pin_mut;
-use log::debug;
-use log::error;
-use log::info;
-use nix::pty::openpty;
-use nix::pty::OpenptyResult;
-use nix::sys::signal::Signal;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::dup2;
-use nix::unistd::execve;
-use nix::unistd::pipe;
-use nix::unistd::setsid;
-use nix::unistd::ForkResult;
-use nix::unistd::{close, fork, Pid};
-use std::convert::From;
-use std::env;
-use std::ffi::CString;
-use std::fs::File;
-use std::mem;
-use std::os::fd::AsRawFd;
-use std::os::fd::OwnedFd;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::FromRawFd;
 use std::os::unix::prelude::RawFd;
-use std::path::Path;
-use std::pin::Pin;
-use tokio::io::AsyncBufReadExt;
-use tokio::io::BufReader;
-use tokio::sync::oneshot;
-use tokio_fd::AsyncFd;
-use tokio_stream::wrappers::LinesStream;
-use tokio_stream::{Stream, StreamExt, StreamMap};
-use which::which;
 
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
 pub enum StdioType {
     Stdout,
     Stderr,
+    /// A pipe set up with 'XCommandBuilder::side_channel()', by its fd number in the child
+    SideChannel(RawFd),
+    /// What the child wrote to the terminal given with 'XCommandBuilder::pty_stdin()', such as
+    /// prompts written to /dev/tty, and the echo of its input
+    Terminal,
 }
 
-#[derive(Debug, Clone)]
-pub enum XStatus {
-    Exited(i32),
-    Signaled(Signal),
-    Running,
-}
+impl StdioType {
+    /// A short name, e.g. "stderr" or "fd 3", used in tee files and replay logs
+    pub(crate) fn tag(self) -> String {
+        match self {
+            Self::Stdout => "stdout".to_string(),
+            Self::Stderr => "stderr".to_string(),
+            Self::SideChannel(fd) => format!("fd {}", fd),
+            Self::Terminal => "terminal".to_string(),
+        }
+    }
 
-impl From<WaitStatus> for XStatus {
-    fn from(input: WaitStatus) -> Self {
-        match input {
-            WaitStatus::Exited(_, code) => Self::Exited(code),
-            WaitStatus::Signaled(_, signal, _) | WaitStatus::Stopped(_, signal) => {
-                Self::Signaled(signal)
-            }
-            _ => Self::Running,
+    pub(crate) fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "stdout" => Some(Self::Stdout),
+            "stderr" => Some(Self::Stderr),
+            "terminal" => Some(Self::Terminal),
+            _ => tag
+                .strip_prefix("fd ")
+                .and_then(|fd| fd.parse().ok())
+                .map(Self::SideChannel),
         }
     }
 }
@@ -63,15 +38,69 @@ impl From<WaitStatus> for XStatus {
 mod builder;
 pub use builder::XCommandBuilder;
 
+mod child_error;
+
+mod exec_args;
+
+mod backend;
+pub use backend::SpawnBackend;
+
 mod command;
 pub use command::XCommand;
 
 mod child_handle;
-pub use child_handle::XChildHandle;
+pub use child_handle::{XChildHandle, XStreamer};
 
 mod env_var;
 pub use env_var::EnvVar;
 
+mod status;
+pub use status::XStatus;
+
+mod exit;
+pub use exit::XExit;
+
+mod monitor;
+pub use monitor::XSample;
+
+mod scheduling;
+pub use scheduling::IoPriority;
+
+mod parse;
+pub use parse::ParseError;
+
+mod secret;
+
+mod read;
+
+mod pipeline;
+pub use pipeline::{pipefail, XPipeline, XPipelineHandle, XPipelineStreamer};
+
+mod expect;
+pub use expect::{Pattern, TranscriptEntry, XExpect, XMatch};
+
+mod hub;
+pub use hub::{Replay, XHub};
+
+mod drain;
+pub use drain::{DrainPolicy, XDrainStats};
+
+mod tee;
+pub use tee::TeeOptions;
+
+mod record;
+
+mod replay;
+pub use replay::{ReplayChild, ReplayStreamer, ReplayTiming};
+
+mod child;
+pub use child::{XChild, XChildStreamer};
+
+mod ready;
+
+mod script;
+pub use script::{Redirect, ScriptNode, XScript, XScriptEvent};
+
 /*
 pub async fn run<P: AsRef<Path>>(command: P, args: &[String]) -> Result<i32> {
     let command: &Path = command.as_ref();
@@ -0,0 +1,239 @@
+use crate::exit::Reaped;
+use async_stream::stream;
+use eyre::Result;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+use tokio_stream::Stream;
+
+/// A point-in-time reading of a running process, or of a process and its descendants
+#[derive(Debug, Clone, PartialEq)]
+pub struct XSample {
+    /// Time since monitoring started
+    pub elapsed: Duration,
+    /// Number of processes that contributed to this sample
+    pub processes: usize,
+    /// CPU usage since the previous sample, where 100.0 is one fully used core.
+    /// Always 0.0 for the first sample
+    pub cpu_percent: f64,
+    /// Resident set size in bytes
+    pub rss: u64,
+    pub threads: u64,
+    pub open_fds: u64,
+    /// Bytes read through read()-like syscalls, including reads served from the page cache
+    pub read_bytes: u64,
+    /// Bytes written through write()-like syscalls
+    pub write_bytes: u64,
+}
+
+/// The fields of /proc/<pid>/stat that we care about
+struct Stat {
+    state: char,
+    ppid: i32,
+    /// utime + stime, in clock ticks
+    cpu_ticks: u64,
+    threads: u64,
+    /// In pages
+    rss: u64,
+}
+
+fn read_stat(pid: i32) -> io::Result<Stat> {
+    let contents = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command name is wrapped in parens and may itself contain spaces or parens, so skip
+    // past the last ')' before splitting
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed stat file");
+    let rest = contents
+        .rfind(')')
+        .map(|i| &contents[i + 1..])
+        .ok_or_else(invalid)?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    // Field numbers from proc(5), minus the two we skipped
+    let field = |n: usize| -> io::Result<u64> {
+        fields
+            .get(n - 3)
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(invalid)
+    };
+    Ok(Stat {
+        state: fields
+            .first()
+            .and_then(|s| s.chars().next())
+            .ok_or_else(invalid)?,
+        ppid: field(4)? as i32,
+        cpu_ticks: field(14)? + field(15)?,
+        threads: field(20)?,
+        rss: field(24)?,
+    })
+}
+
+/// (rchar, wchar) from /proc/<pid>/io
+fn read_io(pid: i32) -> io::Result<(u64, u64)> {
+    let contents = fs::read_to_string(format!("/proc/{}/io", pid))?;
+    let mut read = 0;
+    let mut write = 0;
+    for line in contents.lines() {
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim().parse().unwrap_or(0);
+            match key {
+                "rchar" => read = value,
+                "wchar" => write = value,
+                _ => {}
+            }
+        }
+    }
+    Ok((read, write))
+}
+
+fn count_fds(pid: i32) -> io::Result<u64> {
+    Ok(fs::read_dir(format!("/proc/{}/fd", pid))?.count() as u64)
+}
+
+/// All live descendants of 'root'
+fn descendants(root: i32) -> Vec<i32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        if let Ok(stat) = read_stat(pid) {
+            children.entry(stat.ppid).or_default().push(pid);
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut queue = vec![root];
+    while let Some(pid) = queue.pop() {
+        if let Some(kids) = children.get(&pid) {
+            found.extend(kids);
+            queue.extend(kids);
+        }
+    }
+    found
+}
+
+fn sysconf(name: libc::c_int) -> u64 {
+    match unsafe { libc::sysconf(name) } {
+        n if n > 0 => n as u64,
+        _ => 0,
+    }
+}
+
+struct Sampler {
+    root: i32,
+    descendants: bool,
+    ticks_per_second: u64,
+    page_size: u64,
+    start: Instant,
+    /// CPU ticks of each process as of the previous sample
+    previous: Option<(Instant, HashMap<i32, u64>)>,
+}
+
+impl Sampler {
+    /// Returns None once the root process is gone
+    fn sample(&mut self) -> Option<XSample> {
+        let root = read_stat(self.root).ok()?;
+        if root.state == 'Z' {
+            return None;
+        }
+
+        let mut stats = vec![(self.root, root)];
+        if self.descendants {
+            for pid in descendants(self.root) {
+                // Processes may exit while we are looking at them
+                if let Ok(stat) = read_stat(pid) {
+                    stats.push((pid, stat));
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let mut sample = XSample {
+            elapsed: now - self.start,
+            processes: stats.len(),
+            cpu_percent: 0.0,
+            rss: 0,
+            threads: 0,
+            open_fds: 0,
+            read_bytes: 0,
+            write_bytes: 0,
+        };
+        let mut ticks = HashMap::with_capacity(stats.len());
+        let mut used_ticks = 0;
+        for (pid, stat) in &stats {
+            sample.rss += stat.rss * self.page_size;
+            sample.threads += stat.threads;
+            sample.open_fds += count_fds(*pid).unwrap_or(0);
+            let (read, write) = read_io(*pid).unwrap_or((0, 0));
+            sample.read_bytes += read;
+            sample.write_bytes += write;
+
+            if let Some((_, previous)) = &self.previous {
+                let before = previous.get(pid).copied().unwrap_or(0);
+                used_ticks += stat.cpu_ticks.saturating_sub(before);
+            }
+            ticks.insert(*pid, stat.cpu_ticks);
+        }
+
+        if let Some((at, _)) = &self.previous {
+            let seconds = (now - *at).as_secs_f64();
+            if seconds > 0.0 && self.ticks_per_second > 0 {
+                sample.cpu_percent =
+                    used_ticks as f64 / self.ticks_per_second as f64 / seconds * 100.0;
+            }
+        }
+        self.previous = Some((now, ticks));
+        Some(sample)
+    }
+}
+
+/// Sample 'pid' every 'interval' until it exits or is reaped
+pub(crate) fn monitor(
+    pid: Pid,
+    interval: Duration,
+    descendants: bool,
+    reaped: Arc<Reaped>,
+) -> impl Stream<Item = Result<XSample>> {
+    stream! {
+        let mut sampler = Sampler {
+            root: pid.as_raw(),
+            descendants,
+            ticks_per_second: sysconf(libc::_SC_CLK_TCK),
+            page_size: sysconf(libc::_SC_PAGESIZE),
+            start: Instant::now(),
+            previous: None,
+        };
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            // Once reaped, the pid may already belong to an unrelated process
+            if reaped.get() {
+                return;
+            }
+            // procfs reads are cheap but still blocking, and walking the whole tree is not
+            let result = tokio::task::spawn_blocking(move || {
+                let sample = sampler.sample();
+                (sampler, sample)
+            })
+            .await;
+            match result {
+                Ok((returned, Some(sample))) => {
+                    sampler = returned;
+                    yield Ok(sample);
+                }
+                Ok((_, None)) => return,
+                Err(e) => {
+                    yield Err(e.into());
+                    return;
+                }
+            }
+        }
+    }
+}
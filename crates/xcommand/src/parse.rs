@@ -0,0 +1,593 @@
+use crate::env_var::EnvVar;
+use crate::script::{Redirect, ScriptNode};
+use std::borrow::Cow;
+use std::os::unix::prelude::RawFd;
+use thiserror::Error;
+use winnow::ascii::digit1;
+use winnow::combinator::{alt, cut_err, delimited, dispatch, empty, fail, opt, peek, preceded};
+use winnow::combinator::{repeat, terminated};
+use winnow::error::{AddContext, ContextError, ErrMode, ParseError as WinnowError, StrContext};
+use winnow::stream::AsChar;
+use winnow::stream::Stream;
+use winnow::token::{any, one_of, take_till, take_while};
+use winnow::{ModalResult, Parser};
+
+/// A command line or script that couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{message} at column {column}")]
+pub struct ParseError {
+    /// 1-based, counted in characters
+    pub column: usize,
+    pub message: String,
+}
+
+/// Quote a word for a POSIX shell, so that 'split()' or a shell gives it back unchanged.
+/// Words made of characters no shell treats specially are left bare
+pub(crate) fn quote(word: &str) -> Cow<'_, str> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        return Cow::Borrowed(word);
+    }
+    // Nothing is special within single quotes, so only they need escaping, by closing the
+    // quotes and escaping the quote outside them
+    Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+}
+
+/// Like 'quote()', for a word that may not be valid UTF-8. Such words are written in '$'...''
+/// quotes with the other bytes escaped as '\xHH', which bash, zsh and ksh understand as well
+pub(crate) fn quote_bytes(word: &[u8]) -> Cow<'_, str> {
+    if let Ok(word) = std::str::from_utf8(word) {
+        return quote(word);
+    }
+    let mut quoted = String::with_capacity(word.len() + 3);
+    quoted.push_str("$'");
+    for &byte in word {
+        match byte {
+            b'\'' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            }
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('\'');
+    Cow::Owned(quoted)
+}
+
+/// Variables to expand '$VAR' and '${VAR:-default}' against, or None to keep '$' literal
+type Env<'e> = Option<&'e [EnvVar]>;
+
+/// Split a command line into words the way a POSIX shell would, without running one.
+/// Expansions are not split on whitespace, and a word that is nothing but unquoted expansions
+/// that came out empty is dropped, as the shell would. Operators ('|', ';', '>', ...) and
+/// command substitution are rejected rather than passed on as arguments. Words are bytes, as
+/// '$'\xHH'' escapes needn't make valid UTF-8
+pub(crate) fn split(line: &str, env: Env) -> Result<Vec<Vec<u8>>, ParseError> {
+    let mut words = |input: &mut &str| words(input, env);
+    words
+        .parse(line)
+        .map_err(|error| parse_error(line, error, "invalid command line"))
+}
+
+/// Parse a script of the command language that 'XScript' runs
+pub(crate) fn script(text: &str, env: Env) -> Result<ScriptNode, ParseError> {
+    let mut script = terminated(
+        |input: &mut &str| list(input, env),
+        |input: &mut &str| end(input, None),
+    );
+    script
+        .parse(text)
+        .map_err(|error| parse_error(text, error, "invalid script"))
+}
+
+fn parse_error(text: &str, error: WinnowError<&str, ContextError>, fallback: &str) -> ParseError {
+    let offset = error.offset();
+    let message = error
+        .inner()
+        .context()
+        .find_map(|context| match context {
+            StrContext::Label(label) => Some(label.to_string()),
+            _ => None,
+        })
+        .unwrap_or_else(|| fallback.to_string());
+    ParseError {
+        column: text[..offset].chars().count() + 1,
+        message,
+    }
+}
+
+fn words(input: &mut &str, env: Env) -> ModalResult<Vec<Vec<u8>>> {
+    let word = |input: &mut &str| word(input, env);
+    let words: Vec<Option<Vec<u8>>> =
+        preceded(blanks, repeat(0.., terminated(word, blanks))).parse_next(input)?;
+    if !input.is_empty() {
+        // Words end at operators
+        let label = "shell operators are not supported";
+        return cut_err(fail)
+            .context(StrContext::Label(label))
+            .parse_next(input);
+    }
+    Ok(words.into_iter().flatten().collect())
+}
+
+/// Commands separated by ';' or newlines
+fn list(input: &mut &str, env: Env) -> ModalResult<ScriptNode> {
+    blanks(input)?;
+    let mut nodes = Vec::new();
+    while let Some(node) = opt(|input: &mut &str| and_or(input, env)).parse_next(input)? {
+        nodes.push(node);
+        inline_blanks(input)?;
+        if opt(one_of([';', '\n'])).parse_next(input)?.is_none() {
+            break;
+        }
+        blanks(input)?;
+    }
+    match nodes.len() {
+        1 => Ok(nodes.pop().unwrap()),
+        _ => Ok(ScriptNode::Sequence(nodes)),
+    }
+}
+
+/// Commands joined by '&&' and '||', which bind equally tightly, from the left
+fn and_or(input: &mut &str, env: Env) -> ModalResult<ScriptNode> {
+    let mut node = unit(input, env)?;
+    loop {
+        inline_blanks(input)?;
+        let Some(op) = opt(alt(("&&", "||"))).parse_next(input)? else {
+            return Ok(node);
+        };
+        // The next command may be on the next line
+        blanks(input)?;
+        let label = StrContext::Label("expected a command after '&&' or '||'");
+        let next = cut_err(|input: &mut &str| unit(input, env))
+            .context(label)
+            .parse_next(input)?;
+        node = match op {
+            "&&" => ScriptNode::And(Box::new(node), Box::new(next)),
+            _ => ScriptNode::Or(Box::new(node), Box::new(next)),
+        };
+    }
+}
+
+fn unit(input: &mut &str, env: Env) -> ModalResult<ScriptNode> {
+    if input.starts_with('(') {
+        subshell(input, env)
+    } else {
+        command(input, env)
+    }
+}
+
+/// '( list )' followed by redirections
+fn subshell(input: &mut &str, env: Env) -> ModalResult<ScriptNode> {
+    let start = input.checkpoint();
+    '('.parse_next(input)?;
+    let body = list(input, env)?;
+    blanks(input)?;
+    if input.is_empty() {
+        return Err(error_at(input, &start, "unterminated '('"));
+    }
+    end(input, Some(')'))?;
+    let redirects = repeat(
+        0..,
+        preceded(inline_blanks, |input: &mut &str| redirect(input, env)),
+    )
+    .parse_next(input)?;
+    Ok(ScriptNode::Subshell {
+        body: Box::new(body),
+        redirects,
+    })
+}
+
+/// Words and redirections, in any order
+fn command(input: &mut &str, env: Env) -> ModalResult<ScriptNode> {
+    let start = input.checkpoint();
+    let mut words = Vec::new();
+    let mut redirects = Vec::new();
+    let mut empty = true;
+    loop {
+        let before = input.checkpoint();
+        inline_blanks(input)?;
+        if let Some(redirect) = opt(|input: &mut &str| redirect(input, env)).parse_next(input)? {
+            redirects.push(redirect);
+        } else if let Some(word) = opt(|input: &mut &str| word(input, env)).parse_next(input)? {
+            words.extend(word.map(lossy));
+        } else {
+            input.reset(&before);
+            break;
+        }
+        empty = false;
+    }
+    if empty {
+        return fail.parse_next(input);
+    }
+    if words.is_empty() {
+        return Err(error_at(input, &start, "expected a command"));
+    }
+    Ok(ScriptNode::Command { words, redirects })
+}
+
+/// '[n]> path', '[n]>> path', '< path' or 'n>&m'
+fn redirect(input: &mut &str, env: Env) -> ModalResult<Redirect> {
+    let start = input.checkpoint();
+    let fd = opt(digit1.parse_to::<RawFd>()).parse_next(input)?;
+    let op = alt((">>", ">&", ">", "<")).parse_next(input)?;
+    let fd = match (op, fd) {
+        ("<", None | Some(0)) => 0,
+        (">>" | ">&" | ">", None) => 1,
+        (">>" | ">&" | ">", Some(fd @ (1 | 2))) => fd,
+        ("<", _) => {
+            return Err(error_at(
+                input,
+                &start,
+                "only stdin can be read from a file",
+            ))
+        }
+        _ => {
+            return Err(error_at(
+                input,
+                &start,
+                "only stdout and stderr can be redirected",
+            ))
+        }
+    };
+    inline_blanks(input)?;
+
+    if op == ">&" {
+        let target = input.checkpoint();
+        let label = StrContext::Label("expected 1 or 2 after '>&'");
+        return match cut_err(digit1.parse_to::<RawFd>())
+            .context(label)
+            .parse_next(input)?
+        {
+            target @ (1 | 2) => Ok(Redirect::Duplicate { fd, target }),
+            _ => Err(error_at(input, &target, "expected 1 or 2 after '>&'")),
+        };
+    }
+    let path_start = input.checkpoint();
+    let label = StrContext::Label("expected a file name");
+    let path = cut_err(|input: &mut &str| word(input, env))
+        .context(label)
+        .parse_next(input)?;
+    let Some(path) = path.map(lossy) else {
+        return Err(error_at(input, &path_start, "ambiguous redirect"));
+    };
+    Ok(match op {
+        "<" => Redirect::Read { path },
+        _ => Redirect::Write {
+            fd,
+            path,
+            append: op == ">>",
+        },
+    })
+}
+
+/// The end of the script, or the 'closing' character of a subshell. Anything else is syntax the
+/// language doesn't have
+fn end(input: &mut &str, closing: Option<char>) -> ModalResult<()> {
+    let label = match input.chars().next() {
+        None if closing.is_none() => return Ok(()),
+        Some(c) if Some(c) == closing => {
+            any.parse_next(input)?;
+            return Ok(());
+        }
+        None => "unexpected end of script",
+        Some('|') => "pipes are not supported",
+        Some('&') => "background jobs are not supported",
+        Some(')') => "unmatched ')'",
+        Some(_) => "unexpected character",
+    };
+    cut_err(fail)
+        .context(StrContext::Label(label))
+        .parse_next(input)
+}
+
+/// Spaces, tabs and line continuations, but not the newlines that separate commands
+fn inline_blanks(input: &mut &str) -> ModalResult<()> {
+    repeat(0.., alt((one_of([' ', '\t', '\r']).void(), "\\\n".void()))).parse_next(input)
+}
+
+/// Whitespace and line continuations between words
+fn blanks(input: &mut &str) -> ModalResult<()> {
+    repeat(
+        0..,
+        alt((one_of([' ', '\t', '\r', '\n']).void(), "\\\n".void())),
+    )
+    .parse_next(input)
+}
+
+/// A word, or None if it was nothing but unquoted expansions that came out empty
+fn word(input: &mut &str, env: Env) -> ModalResult<Option<Vec<u8>>> {
+    let piece = |input: &mut &str| piece(input, env);
+    let (word, kept) = repeat(1.., piece)
+        .fold(
+            || (Vec::new(), false),
+            |(mut word, kept), (text, literal)| {
+                word.extend_from_slice(&text);
+                (word, kept || literal)
+            },
+        )
+        .parse_next(input)?;
+    Ok((kept || !word.is_empty()).then_some(word))
+}
+
+/// Part of a word, and whether it counts as literal text (anything but an unquoted expansion)
+fn piece(input: &mut &str, env: Env) -> ModalResult<(Vec<u8>, bool)> {
+    dispatch! {peek(any);
+        ' ' | '\t' | '\r' | '\n' => fail,
+        '|' | '&' | ';' | '<' | '>' | '(' | ')' => fail,
+        '`' => cut_err(fail).context(StrContext::Label("command substitution is not supported")),
+        '\'' => single_quoted.map(|text| (text.as_bytes().to_vec(), true)),
+        '"' => (|input: &mut &str| double_quoted(input, env)).map(|text| (text, true)),
+        '\\' => escaped.map(|text| (text, true)),
+        '$' if env.is_some() => alt((
+            ansi_c_quoted.map(|text| (text, true)),
+            (|input: &mut &str| expansion(input, env)).map(|text| (text, false)),
+        )),
+        '$' => '$'.value((b"$".to_vec(), true)),
+        _ => take_till(1.., [' ', '\t', '\r', '\n', '\'', '"', '\\', '$', '|', '&', ';', '<', '>', '(', ')', '`'])
+            .map(|text: &str| (text.as_bytes().to_vec(), true)),
+    }
+    .parse_next(input)
+}
+
+/// Everything up to the next single quote, taken literally
+fn single_quoted<'i>(input: &mut &'i str) -> ModalResult<&'i str> {
+    let parser = delimited('\'', take_till(0.., '\''), '\'');
+    unterminated(parser, "unterminated single quote").parse_next(input)
+}
+
+/// Everything up to the next unescaped single quote of '$'...'', with the escapes of C strings
+/// decoded, e.g. '\n', '\x7f' and '\177'
+fn ansi_c_quoted(input: &mut &str) -> ModalResult<Vec<u8>> {
+    let mut escape = |input: &mut &str| {
+        let label = StrContext::Label("trailing backslash");
+        let hex = preceded('x', take_while(1..=2, AsChar::is_hex_digit))
+            .map(|digits| u8::from_str_radix(digits, 16).unwrap());
+        // Like bash, values past a byte wrap
+        let octal = take_while(1..=3, AsChar::is_oct_digit)
+            .map(|digits| u32::from_str_radix(digits, 8).unwrap() as u8);
+        let other = cut_err(any).context(label).map(|c| match c {
+            'n' => vec![b'\n'],
+            't' => vec![b'\t'],
+            'r' => vec![b'\r'],
+            'a' => vec![0x07],
+            'b' => vec![0x08],
+            'e' | 'E' => vec![0x1b],
+            'f' => vec![0x0c],
+            'v' => vec![0x0b],
+            '\\' | '\'' | '"' | '?' => vec![c as u8],
+            // Anything else keeps its backslash
+            c => format!("\\{}", c).into_bytes(),
+        });
+        preceded(
+            '\\',
+            alt((
+                hex.map(|byte| vec![byte]),
+                octal.map(|byte| vec![byte]),
+                other,
+            )),
+        )
+        .parse_next(input)
+    };
+    let piece = dispatch! {peek(any);
+        '\'' => fail,
+        '\\' => escape,
+        _ => take_till(1.., ['\'', '\\']).map(|text: &str| text.as_bytes().to_vec()),
+    };
+    // Any other '$' backtracks, to be taken as an expansion
+    peek("$'").parse_next(input)?;
+    let parser = delimited("$'", repeat(0.., piece).fold(Vec::new, push_bytes), '\'');
+    unterminated(parser, "unterminated single quote").parse_next(input)
+}
+
+/// Only '$', '`', '"', '\' and newlines can be escaped within double quotes
+fn double_quoted(input: &mut &str, env: Env) -> ModalResult<Vec<u8>> {
+    let mut escaped = preceded('\\', any).map(|c| match c {
+        '$' | '`' | '"' | '\\' => c.to_string().into_bytes(),
+        '\n' => Vec::new(),
+        c => format!("\\{}", c).into_bytes(),
+    });
+    let mut expansion = |input: &mut &str| expansion(input, env);
+    let piece = dispatch! {peek(any);
+        '"' => fail,
+        '\\' => escaped,
+        '$' => expansion,
+        '`' => cut_err(fail).context(StrContext::Label("command substitution is not supported")),
+        _ => take_till(1.., ['"', '\\', '$', '`']).map(|text: &str| text.as_bytes().to_vec()),
+    };
+    let parser = delimited('"', repeat(0.., piece).fold(Vec::new, push_bytes), '"');
+    unterminated(parser, "unterminated double quote").parse_next(input)
+}
+
+/// A backslash outside quotes keeps the next character literal, or joins lines
+fn escaped(input: &mut &str) -> ModalResult<Vec<u8>> {
+    let escaped = cut_err(any).context(StrContext::Label("trailing backslash"));
+    preceded('\\', escaped)
+        .map(|c| match c {
+            '\n' => Vec::new(),
+            c => c.to_string().into_bytes(),
+        })
+        .parse_next(input)
+}
+
+/// '$NAME' or '${NAME}', or '${NAME:-default}' for a default when NAME is unset or empty.
+/// Unset variables expand to nothing, and a '$' that doesn't start a name is literal
+fn expansion(input: &mut &str, env: Env) -> ModalResult<Vec<u8>> {
+    let Some(env) = env else {
+        return '$'.value(b"$".to_vec()).parse_next(input);
+    };
+    let braced = |input: &mut &str| {
+        let name = cut_err(name).context(StrContext::Label("expected a variable name"));
+        let default = preceded(":-", |input: &mut &str| default(input, env));
+        let closing = cut_err('}').context(StrContext::Label("expected '}'"));
+        (name, opt(default), closing)
+            .map(|(name, default, _)| match (lookup(env, name), default) {
+                (Some(value), _) if !value.is_empty() => value,
+                (_, Some(default)) => default,
+                (value, None) => value.unwrap_or_default(),
+            })
+            .parse_next(input)
+    };
+    preceded(
+        '$',
+        alt((
+            preceded('{', braced),
+            name.map(|name| lookup(env, name).unwrap_or_default()),
+            empty.value(b"$".to_vec()),
+        )),
+    )
+    .parse_next(input)
+}
+
+/// The default in '${NAME:-default}', which may be quoted and contain expansions itself
+fn default(input: &mut &str, env: &[EnvVar]) -> ModalResult<Vec<u8>> {
+    let mut expansion = |input: &mut &str| expansion(input, Some(env));
+    let mut double_quoted = |input: &mut &str| double_quoted(input, Some(env));
+    let piece = dispatch! {peek(any);
+        '}' => fail,
+        '\'' => single_quoted.map(|text| text.as_bytes().to_vec()),
+        '"' => double_quoted,
+        '\\' => escaped,
+        '$' => expansion,
+        '`' => cut_err(fail).context(StrContext::Label("command substitution is not supported")),
+        _ => take_till(1.., ['}', '\'', '"', '\\', '$', '`']).map(|text: &str| text.as_bytes().to_vec()),
+    };
+    repeat(0.., piece)
+        .fold(Vec::new, push_bytes)
+        .parse_next(input)
+}
+
+fn name<'i>(input: &mut &'i str) -> ModalResult<&'i str> {
+    (
+        one_of(|c: char| c.is_ascii_alphabetic() || c == '_'),
+        take_while(0.., |c: char| c.is_ascii_alphanumeric() || c == '_'),
+    )
+        .take()
+        .parse_next(input)
+}
+
+fn lookup(env: &[EnvVar], name: &str) -> Option<Vec<u8>> {
+    env.iter()
+        .find(|var| var.key.as_bytes() == name.as_bytes())
+        .map(|var| var.value.as_bytes().to_vec())
+}
+
+fn push_bytes(mut acc: Vec<u8>, text: Vec<u8>) -> Vec<u8> {
+    acc.extend_from_slice(&text);
+    acc
+}
+
+/// Scripts are run from strings
+fn lossy(word: Vec<u8>) -> String {
+    String::from_utf8_lossy(&word).into_owned()
+}
+
+/// Report a quote or brace that was never closed at its opening, rather than at the end of the
+/// line where the closing one was looked for
+fn unterminated<'i, O>(
+    mut parser: impl Parser<&'i str, O, ContextError>,
+    label: &'static str,
+) -> impl Parser<&'i str, O, ContextError> {
+    move |input: &mut &'i str| {
+        let start = input.checkpoint();
+        parser.parse_next(input).map_err(|error| match error {
+            ErrMode::Backtrack(_) => error_at(input, &start, label),
+            error => error,
+        })
+    }
+}
+
+/// A fatal error reported at 'start'
+fn error_at<'i>(
+    input: &mut &'i str,
+    start: &<&'i str as Stream>::Checkpoint,
+    label: &'static str,
+) -> ErrMode<ContextError> {
+    input.reset(start);
+    ErrMode::Cut(ContextError::new().add_context(input, start, StrContext::Label(label)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> Vec<EnvVar> {
+        vec![
+            EnvVar::from_str_pair("HOME", "/home/me").unwrap(),
+            EnvVar::from_str_pair("EMPTY", "").unwrap(),
+        ]
+    }
+
+    fn split_str(line: &str) -> Result<Vec<String>, ParseError> {
+        let env = env();
+        let words = split(line, Some(&env))?;
+        Ok(words.into_iter().map(lossy).collect())
+    }
+
+    #[test]
+    fn expansions() {
+        let cases = [
+            ("echo $HOME", vec!["echo", "/home/me"]),
+            ("echo ${HOME}/bin", vec!["echo", "/home/me/bin"]),
+            ("echo x${HOME}y", vec!["echo", "x/home/mey"]),
+            ("echo ${NOPE:-a b}", vec!["echo", "a b"]),
+            ("echo ${EMPTY:-\"$HOME\"}", vec!["echo", "/home/me"]),
+            ("echo ${HOME:-dflt}", vec!["echo", "/home/me"]),
+            ("echo $NOPE end", vec!["echo", "end"]),
+            ("echo \"$NOPE\"", vec!["echo", ""]),
+            ("echo \"$HOME/x\"", vec!["echo", "/home/me/x"]),
+            ("echo a$", vec!["echo", "a$"]),
+            ("echo $ $1", vec!["echo", "$", "$1"]),
+            ("echo '$HOME'", vec!["echo", "$HOME"]),
+            ("echo $'a\\tb'", vec!["echo", "a\tb"]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(split_str(line).unwrap(), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn dollar_is_literal_without_an_env() {
+        let words = split("echo $HOME $'x'", None).unwrap();
+        let words: Vec<_> = words.into_iter().map(lossy).collect();
+        assert_eq!(words, ["echo", "$HOME", "$x"]);
+    }
+
+    #[test]
+    fn errors_are_reported_at_their_column() {
+        let cases = [
+            ("echo ${HOME", 12, "expected '}'"),
+            ("echo ${}", 8, "expected a variable name"),
+            ("echo 'abc", 6, "unterminated single quote"),
+            ("echo \"abc", 6, "unterminated double quote"),
+            ("echo $'abc", 6, "unterminated single quote"),
+            ("echo abc\\", 10, "trailing backslash"),
+            ("echo `id`", 6, "command substitution is not supported"),
+            ("echo a | cat", 8, "shell operators are not supported"),
+            ("héllo wörld 'x", 13, "unterminated single quote"),
+        ];
+        for (line, column, message) in cases {
+            let error = split_str(line).unwrap_err();
+            let expected = ParseError {
+                column,
+                message: message.to_string(),
+            };
+            assert_eq!(error, expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn scripts_expand_and_report_columns() {
+        let env = env();
+        let node = script("/bin/echo $HOME", Some(&env)).unwrap();
+        let ScriptNode::Command { words, .. } = node else {
+            panic!("not a command: {:?}", node);
+        };
+        assert_eq!(words, ["/bin/echo", "/home/me"]);
+
+        let error = script("/bin/echo ok; /bin/echo ${HOME", Some(&env)).unwrap_err();
+        assert_eq!(error.message, "expected '}'");
+        assert_eq!(error.column, 31);
+    }
+}
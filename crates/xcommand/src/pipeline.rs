@@ -0,0 +1,227 @@
+use crate::command::{open_pty, Sink, StageIo};
+use crate::read::read_chunks;
+use crate::StdioType;
+use crate::XChildHandle;
+use crate::XCommand;
+use crate::XStatus;
+use crate::XStreamer;
+use eyre::bail;
+use eyre::Result;
+use nix::fcntl::OFlag;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::{pipe2, Pid};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::thread;
+use tokio_stream::{Stream, StreamExt, StreamMap};
+
+/// How a stage's stdout reaches the next stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    Pipe,
+    /// Through a pty, so that the stage believes it is writing to a terminal
+    Pty,
+}
+
+/// Commands chained like 'producer | filter | consumer'. The last stage's stdout is a pty, as
+/// it is for a single XCommand, and every stage's stderr is a pty of its own
+#[derive(Debug, Default)]
+pub struct XPipeline {
+    stages: Vec<(XCommand, Link)>,
+}
+
+impl XPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a stage, whose stdout is piped to the next stage
+    pub fn stage(mut self, command: XCommand) -> Self {
+        self.stages.push((command, Link::Pipe));
+        self
+    }
+
+    /// Add a stage whose stdout is a pty, relayed to the next stage through a pipe
+    pub fn pty_stage(mut self, command: XCommand) -> Self {
+        self.stages.push((command, Link::Pty));
+        self
+    }
+
+    /// Spawn every stage. If a stage fails to spawn, the ones before it are killed
+    pub fn spawn(&self) -> Result<XPipelineHandle> {
+        if self.stages.is_empty() {
+            bail!("A pipeline needs at least one stage");
+        }
+
+        let mut children: Vec<XChildHandle> = Vec::with_capacity(self.stages.len());
+        let mut stdin = None;
+        for (i, (command, link)) in self.stages.iter().enumerate() {
+            let last = i + 1 == self.stages.len();
+            let spawned = (|| {
+                if last {
+                    let io = StageIo {
+                        stdin: stdin.take(),
+                        ..StageIo::default()
+                    };
+                    return command.spawn_with(io);
+                }
+
+                let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+                let child = match link {
+                    Link::Pipe => {
+                        let io = StageIo {
+                            stdin: stdin.take(),
+                            stdout: Sink::Fd(write),
+                            ..StageIo::default()
+                        };
+                        command.spawn_with(io)?
+                    }
+                    Link::Pty => {
+                        let (master, slave) = open_pty(None)?;
+                        // Pass the output through as it was written, without '\r's added
+                        let mut termios = tcgetattr(&slave)?;
+                        cfmakeraw(&mut termios);
+                        tcsetattr(&slave, SetArg::TCSANOW, &termios)?;
+                        let io = StageIo {
+                            stdin: stdin.take(),
+                            stdout: Sink::Fd(slave),
+                            ..StageIo::default()
+                        };
+                        let child = command.spawn_with(io)?;
+                        relay(master, write)?;
+                        child
+                    }
+                };
+                stdin = Some(read);
+                Ok(child)
+            })();
+
+            match spawned {
+                Ok(mut child) => {
+                    // Until every stage is up, so that dropping the ones before a failure kills
+                    // and reaps them
+                    child.set_kill_on_drop(true);
+                    children.push(child);
+                }
+                Err(e) => {
+                    drop(children);
+                    return Err(e.wrap_err(format!("Unable to spawn stage {} of '{}'", i, self)));
+                }
+            }
+        }
+        for (child, (command, _)) in children.iter_mut().zip(&self.stages) {
+            child.set_kill_on_drop(command.kill_on_drop);
+        }
+        Ok(XPipelineHandle { children })
+    }
+}
+
+/// Copy a pty stage's output into the pipe to the next stage on a thread of its own. The next
+/// stage gets EOF once the pty stage and everything it started have closed the pty
+fn relay(master: OwnedFd, pipe: OwnedFd) -> Result<()> {
+    let master = File::from(master);
+    let mut pipe = File::from(pipe);
+    thread::Builder::new()
+        .name("xpipeline-relay".to_string())
+        .spawn(move || {
+            // The next stage may have exited without reading everything
+            read_chunks(master, |chunk| pipe.write_all(chunk).is_ok());
+        })?;
+    Ok(())
+}
+
+/// Stages separated by ' | '
+impl fmt::Display for XPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (command, _)) in self.stages.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{}", command)?;
+        }
+        Ok(())
+    }
+}
+
+/// The status of a pipeline as with bash's 'pipefail': that of the last stage that didn't
+/// succeed, or success if they all did
+pub fn pipefail(statuses: &[XStatus]) -> Option<XStatus> {
+    statuses
+        .iter()
+        .rev()
+        .find(|status| !status.success())
+        .or(statuses.last())
+        .copied()
+}
+
+#[derive(Debug)]
+pub struct XPipelineHandle {
+    children: Vec<XChildHandle>,
+}
+
+impl XPipelineHandle {
+    /// Pids of the stages, in order
+    pub fn pids(&self) -> Vec<Pid> {
+        self.children.iter().map(XChildHandle::pid).collect()
+    }
+
+    /// The handle of each stage, in order
+    pub fn children(&mut self) -> &mut [XChildHandle] {
+        &mut self.children
+    }
+
+    /// Get a streamer for the output of every stage.
+    /// Can only be created once, see 'XChildHandle::streamer()'
+    pub fn streamer(&mut self) -> Result<XPipelineStreamer> {
+        let streamers = self
+            .children
+            .iter_mut()
+            .map(XChildHandle::streamer)
+            .collect::<Result<_>>()?;
+        Ok(XPipelineStreamer { streamers })
+    }
+
+    /// Wait for every stage to exit, returning their statuses in order.
+    /// 'streamer()' must have been called first
+    pub async fn statuses(&mut self) -> Result<Vec<XStatus>> {
+        let mut statuses = Vec::with_capacity(self.children.len());
+        for child in &mut self.children {
+            statuses.push(child.status().await?);
+        }
+        Ok(statuses)
+    }
+
+    /// Wait for every stage to exit, returning the status of the pipeline, see 'pipefail()'
+    pub async fn status(&mut self) -> Result<XStatus> {
+        let statuses = self.statuses().await?;
+        // There is always at least one stage
+        Ok(pipefail(&statuses).unwrap())
+    }
+}
+
+/// Streams the output of every stage of a pipeline
+#[derive(Debug)]
+pub struct XPipelineStreamer {
+    streamers: Vec<XStreamer>,
+}
+
+impl XPipelineStreamer {
+    /// See 'XStreamer::redact_secrets()'
+    pub fn redact_secrets(&mut self, redact: bool) {
+        for streamer in &mut self.streamers {
+            streamer.redact_secrets(redact);
+        }
+    }
+
+    /// Lines of output tagged with the index of the stage they came from: the last stage's
+    /// stdout, and every stage's stderr and side channels
+    pub fn stream(&mut self) -> impl Stream<Item = Result<(usize, StdioType, String)>> + '_ {
+        let mut map = StreamMap::with_capacity(self.streamers.len());
+        for (i, streamer) in self.streamers.iter_mut().enumerate() {
+            map.insert(i, streamer.stream());
+        }
+        map.map(|(i, item)| item.map(|(stdio, line)| (i, stdio, line)))
+    }
+}
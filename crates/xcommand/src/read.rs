@@ -0,0 +1,52 @@
+use std::io::{self, Read};
+
+/// Read 'source' on the calling thread until it ends, passing each chunk to 'chunk' until that
+/// returns false. A pty master fails with EIO once every process has closed the slave, which
+/// ends the output like any other error does
+pub(crate) fn read_chunks(mut source: impl Read, mut chunk: impl FnMut(&[u8]) -> bool) {
+    let mut buf = [0u8; 4096];
+    loop {
+        match source.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => {
+                if !chunk(&buf[..n]) {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Splits chunks of output into lines like the streamer does, without the line endings.
+/// Invalid UTF-8 is replaced
+#[derive(Debug, Default)]
+pub(crate) struct LineSplitter {
+    /// The start of a line whose end hasn't been read yet
+    partial: Vec<u8>,
+}
+
+impl LineSplitter {
+    /// Pass each line that 'chunk' completes to 'line'
+    pub fn push(&mut self, chunk: &[u8], mut line: impl FnMut(String)) {
+        for piece in chunk.split_inclusive(|&byte| byte == b'\n') {
+            match piece.strip_suffix(b"\n") {
+                Some(rest) => {
+                    self.partial.extend_from_slice(rest);
+                    if self.partial.ends_with(b"\r") {
+                        self.partial.pop();
+                    }
+                    line(String::from_utf8_lossy(&self.partial).into_owned());
+                    self.partial.clear();
+                }
+                None => self.partial.extend_from_slice(piece),
+            }
+        }
+    }
+
+    /// The last line, if the output didn't end with a line ending
+    pub fn finish(self) -> Option<String> {
+        (!self.partial.is_empty()).then(|| String::from_utf8_lossy(&self.partial).into_owned())
+    }
+}
@@ -0,0 +1,179 @@
+use crate::expect::Pattern;
+use crate::secret::Secrets;
+use crate::StdioType;
+use crate::XStatus;
+use eyre::bail;
+use eyre::Result;
+use nix::unistd::Pid;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// How many of the child's latest lines are kept for readiness checks and error messages
+const KEPT_LINES: usize = 1000;
+
+/// How many of the kept lines are shown when a readiness check fails
+const SHOWN_LINES: usize = 50;
+
+/// How often probes are retried
+const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait for the rest of a dead child's output before reporting it
+const DRAIN_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct Lines {
+    /// Number of lines ever pushed
+    total: u64,
+    kept: VecDeque<(StdioType, String)>,
+}
+
+/// The latest lines the XStreamer passed on or the XExpect read, shared with the XChildHandle
+/// so it can wait for output without taking it away from them
+#[derive(Debug)]
+pub(crate) struct OutputLog {
+    lines: Mutex<Lines>,
+    /// Publishes the number of lines pushed, to wake up waiters
+    pushed: watch::Sender<u64>,
+    /// Set once the last of the output has been passed on
+    closed: AtomicBool,
+}
+
+impl OutputLog {
+    pub fn new() -> Self {
+        Self {
+            lines: Mutex::new(Lines::default()),
+            pushed: watch::channel(0).0,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn push(&self, stdio: StdioType, line: &str) {
+        let mut lines = self.lines.lock().unwrap();
+        lines.total += 1;
+        lines.kept.push_back((stdio, line.to_string()));
+        if lines.kept.len() > KEPT_LINES {
+            lines.kept.pop_front();
+        }
+        self.pushed.send_replace(lines.total);
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.pushed.send_modify(|_| {});
+    }
+
+    /// The first line after the first 'seen' that matches 'pattern'. 'seen' is moved past the
+    /// lines that were checked
+    fn find(&self, pattern: &Pattern, seen: &mut u64) -> Option<String> {
+        let lines = self.lines.lock().unwrap();
+        let new = (lines.total - *seen).min(lines.kept.len() as u64) as usize;
+        let start = lines.kept.len() - new;
+        *seen = lines.total;
+        lines
+            .kept
+            .range(start..)
+            .find(|(_, line)| pattern.find(line).is_some())
+            .map(|(_, line)| line.clone())
+    }
+
+    /// The latest lines, for error messages
+    fn tail(&self, secrets: &Secrets) -> String {
+        let lines = self.lines.lock().unwrap();
+        if lines.kept.is_empty() {
+            return "No output was captured".to_string();
+        }
+        let skip = lines.kept.len().saturating_sub(SHOWN_LINES);
+        let mut tail = format!("Last {} lines of output:", lines.kept.len() - skip);
+        for (stdio, line) in lines.kept.iter().skip(skip) {
+            tail.push_str(&format!("\n  [{:?}] {}", stdio, secrets.redact(line)));
+        }
+        tail
+    }
+}
+
+/// A child that is being waited on, and what it has output
+pub(crate) struct Readiness<'a> {
+    pub pid: Pid,
+    pub status_rx: watch::Receiver<XStatus>,
+    pub log: &'a OutputLog,
+    pub secrets: &'a Secrets,
+}
+
+impl Readiness<'_> {
+    /// Wait up to 'timeout' for a line of output matching 'pattern', including lines output
+    /// before the call that are still kept
+    pub async fn output(self, pattern: Pattern, timeout: Duration) -> Result<String> {
+        let log = self.log;
+        let what = format!("output matching {}", pattern);
+        let mut seen = 0;
+        self.probe(&what, timeout, || {
+            let found = log.find(&pattern, &mut seen);
+            async move { found }
+        })
+        .await
+    }
+
+    /// Retry 'probe' until it returns something, failing if the child finishes first or
+    /// 'timeout' runs out. 'what' describes what is being waited for
+    pub async fn probe<T, F, Fut>(
+        mut self,
+        what: &str,
+        timeout: Duration,
+        mut probe: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Option<T>>,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut pushed = self.log.pushed.subscribe();
+        // Cleared if the waiter goes away, after which only the deadline can end the wait
+        let mut waited_on = true;
+        loop {
+            if let Some(ready) = probe().await {
+                return Ok(ready);
+            }
+            let status = *self.status_rx.borrow_and_update();
+            if status.finished() {
+                // The last lines are usually the ones that say what went wrong
+                let grace = Instant::now() + DRAIN_GRACE;
+                while !self.log.closed.load(Ordering::SeqCst) {
+                    if tokio::time::timeout_at(grace, pushed.changed())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                // The child may have output what was waited for on its way out
+                if let Some(ready) = probe().await {
+                    return Ok(ready);
+                }
+                bail!(
+                    "Process {} finished ({:?}) while waiting for {}. {}",
+                    self.pid,
+                    status,
+                    what,
+                    self.log.tail(self.secrets)
+                );
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => bail!(
+                    "Timed out after {:?} waiting for {} (process {}). {}",
+                    timeout,
+                    what,
+                    self.pid,
+                    self.log.tail(self.secrets)
+                ),
+                _ = tokio::time::sleep(PROBE_INTERVAL) => {}
+                changed = self.status_rx.changed(), if waited_on => waited_on = changed.is_ok(),
+                _ = pushed.changed() => {}
+            }
+        }
+    }
+}
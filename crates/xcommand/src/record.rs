@@ -0,0 +1,183 @@
+use crate::expect::decode;
+use crate::read::read_chunks;
+use crate::secret::Secrets;
+use crate::XStatus;
+use eyre::Result;
+use eyre::WrapErr;
+use log::debug;
+use nix::fcntl::OFlag;
+use nix::unistd::pipe2;
+use serde_json::json;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Size of a session recorded without 'XCommandBuilder::window_size()' when we aren't running
+/// in a terminal ourselves
+pub(crate) const DEFAULT_WINDOW_SIZE: (u16, u16) = (80, 24);
+
+/// Writes a session as an asciinema v2 .cast file: a JSON header line, then a JSON array per
+/// event with the seconds since the start, the event type and its data. Secrets are masked
+/// throughout. Once the child has finished and all of its output is recorded, an asciinema v3
+/// style exit event is added, whose data is the exit code, e.g. "0", or the name of the signal
+/// that terminated the child, e.g. "SIGKILL", followed by " (core dumped)" if it did
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    /// None once writing has failed, after which the rest of the session is left out
+    file: Mutex<Option<File>>,
+    started: Instant,
+    secrets: Secrets,
+    /// Taps still reading, and the status once the child has finished. The exit event is
+    /// written once there are no taps left and the status is known
+    ending: Mutex<(usize, Option<XStatus>)>,
+}
+
+impl Recorder {
+    /// Create the file at 'path' and write the header. 'command' already has secrets masked, and
+    /// 'env' is what the child has of the variables asciinema records
+    pub fn create(
+        path: &Path,
+        (width, height): (u16, u16),
+        command: &str,
+        env: &[(&str, String)],
+        secrets: Secrets,
+    ) -> Result<Arc<Self>> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        let env: serde_json::Map<_, _> = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), secrets.redact(value).into()))
+            .collect();
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+            "command": command,
+            "env": env,
+        });
+
+        let wrap = || format!("Unable to create recording '{}'", path.display());
+        let mut file = File::create(path).wrap_err_with(wrap)?;
+        file.write_all(format!("{}\n", header).as_bytes())
+            .wrap_err_with(wrap)?;
+        Ok(Arc::new(Self {
+            file: Mutex::new(Some(file)),
+            started: Instant::now(),
+            secrets,
+            ending: Mutex::new((0, None)),
+        }))
+    }
+
+    /// What the child wrote to its terminal, which has already had secrets masked
+    fn output(&self, data: &str) {
+        self.event("o", data);
+    }
+
+    /// What was typed into the child's terminal
+    pub fn input(&self, data: &str) {
+        self.event("i", &self.secrets.redact(data));
+    }
+
+    pub fn resize(&self, cols: u16, rows: u16) {
+        self.event("r", &format!("{}x{}", cols, rows));
+    }
+
+    /// Record how the child finished, once all of its output has been recorded
+    pub fn exited(&self, status: XStatus) {
+        let mut ending = self.ending.lock().unwrap();
+        ending.1 = Some(status);
+        self.end(&ending);
+    }
+
+    fn tap_started(&self) {
+        self.ending.lock().unwrap().0 += 1;
+    }
+
+    fn tap_finished(&self) {
+        let mut ending = self.ending.lock().unwrap();
+        ending.0 -= 1;
+        self.end(&ending);
+    }
+
+    fn end(&self, &(taps, status): &(usize, Option<XStatus>)) {
+        let data = match status {
+            _ if taps > 0 => return,
+            Some(XStatus::Exited(code)) => code.to_string(),
+            Some(XStatus::Signaled {
+                signal,
+                core_dumped,
+            }) => match core_dumped {
+                true => format!("{} (core dumped)", signal),
+                false => signal.to_string(),
+            },
+            _ => return,
+        };
+        self.event("x", &data);
+    }
+
+    fn event(&self, kind: &str, data: &str) {
+        if data.is_empty() {
+            return;
+        }
+        let mut file = self.file.lock().unwrap();
+        let Some(writer) = file.as_mut() else {
+            return;
+        };
+        // Microseconds, like asciinema itself
+        let time = (self.started.elapsed().as_secs_f64() * 1e6).round() / 1e6;
+        let line = format!("{}\n", json!([time, kind, data]));
+        // Written an event at a time, so that a crash leaves a playable recording
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            debug!("Unable to write to recording, leaving out the rest: {}", e);
+            *file = None;
+        }
+    }
+}
+
+/// Record everything read from the pty master 'master' on a thread of its own, and pass it on
+/// unchanged through the pipe that is returned
+pub(crate) fn tap(master: OwnedFd, recorder: Arc<Recorder>) -> Result<OwnedFd> {
+    let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+    let master = File::from(master);
+    let mut pipe = File::from(write);
+    recorder.tap_started();
+    let tapping = recorder.clone();
+    let started = thread::Builder::new()
+        .name("xcommand-record".to_string())
+        .spawn(move || {
+            // A chunk can end partway through a character, which has to be recorded whole
+            let mut partial = Vec::new();
+            // Text held back as it could be the start of a secret, which has to be masked whole
+            let mut held = String::new();
+            let mut record = |text: &str, end: bool| {
+                held.push_str(text);
+                let masked = recorder.secrets.redact(&held).into_owned();
+                let split = match end {
+                    true => masked.len(),
+                    false => recorder.secrets.unfinished(&masked),
+                };
+                recorder.output(&masked[..split]);
+                held = masked[split..].to_string();
+            };
+            read_chunks(master, |chunk| {
+                partial.extend_from_slice(chunk);
+                record(&decode(&mut partial), false);
+                // Whatever was streaming the output has gone away once this fails
+                pipe.write_all(chunk).is_ok()
+            });
+            record(&String::from_utf8_lossy(&partial), true);
+            recorder.tap_finished();
+        });
+    if let Err(e) = started {
+        tapping.tap_finished();
+        return Err(e.into());
+    }
+    Ok(read)
+}
@@ -0,0 +1,327 @@
+use crate::StdioType;
+use crate::XStatus;
+use async_stream::stream;
+use eyre::bail;
+use eyre::Result;
+use eyre::WrapErr;
+use nix::sys::signal::Signal;
+use serde::Deserialize;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::Stream;
+
+/// How a ReplayChild paces its output
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplayTiming {
+    /// As it was recorded
+    #[default]
+    Original,
+    /// The recorded delays multiplied by the factor, so 0.5 plays back twice as fast
+    Scaled(f64),
+    /// All at once
+    Instant,
+}
+
+impl ReplayTiming {
+    /// When something recorded 'time' seconds in is played back
+    fn delay(self, time: f64) -> Option<Duration> {
+        match self {
+            Self::Original => Some(Duration::from_secs_f64(time)),
+            Self::Scaled(factor) => Some(Duration::from_secs_f64(time * factor)),
+            Self::Instant => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Output(StdioType, String),
+    Exit(XStatus),
+}
+
+/// A line of a JSONL replay log, see 'ReplayChild::from_jsonl()'
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogLine {
+    time: f64,
+    stdio: Option<String>,
+    line: Option<String>,
+    exit: Option<i32>,
+    signal: Option<String>,
+    #[serde(default)]
+    core_dumped: bool,
+}
+
+/// A stand-in for an XChildHandle that plays back a recorded session instead of running a
+/// process, for testing code that consumes the output of one. Both implement 'XChild', and
+/// it finishes with the recorded status once all of the output has been streamed
+#[derive(Debug)]
+pub struct ReplayChild {
+    /// (seconds since the start, event), moved into the ReplayStreamer by 'streamer()'
+    events: Option<Vec<(f64, Event)>>,
+    timing: ReplayTiming,
+    /// Moved into the ReplayStreamer, which publishes the status at the end
+    status_tx: Option<Arc<watch::Sender<XStatus>>>,
+    status_rx: watch::Receiver<XStatus>,
+}
+
+impl ReplayChild {
+    /// Play back the file at 'path': an asciinema cast if it ends in '.cast', a JSONL log
+    /// otherwise
+    pub fn open<P: AsRef<Path>>(path: P, timing: ReplayTiming) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Unable to read replay '{}'", path.display()))?;
+        let replay = if path
+            .extension()
+            .is_some_and(|extension| extension == "cast")
+        {
+            Self::from_cast(&text, timing)
+        } else {
+            Self::from_jsonl(&text, timing)
+        };
+        replay.wrap_err_with(|| format!("Unable to replay '{}'", path.display()))
+    }
+
+    /// Play back an asciinema v2 cast, such as one written by 'XCommandBuilder::record()'.
+    /// Its output is split into lines and streamed as stdout. Input, resize and marker events
+    /// are skipped. The status is taken from an asciinema v3 style exit event, '[time, "x",
+    /// "code"]', if there is one, or else is success. As recorded by 'XCommandBuilder::record()',
+    /// a child terminated by a signal has its name instead of the code, e.g. "SIGTERM", followed
+    /// by " (core dumped)" if it did
+    pub fn from_cast(text: &str, timing: ReplayTiming) -> Result<Self> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+        let Some((_, header)) = lines.next() else {
+            bail!("The cast is empty");
+        };
+        let header: serde_json::Value =
+            serde_json::from_str(header).wrap_err("Unable to parse the header of the cast")?;
+        if header["version"] != 2 {
+            bail!(
+                "Only asciinema v2 casts can be replayed, not {}",
+                header["version"]
+            );
+        }
+
+        let mut events = Vec::new();
+        // Output is recorded in chunks, which may end partway through a line
+        let mut partial = String::new();
+        let mut last = 0.0;
+        for (i, line) in lines {
+            let (time, kind, data): (f64, String, String) = serde_json::from_str(line)
+                .wrap_err_with(|| format!("Unable to parse line {} of the cast", i + 1))?;
+            last = time;
+            match kind.as_str() {
+                "o" => {
+                    partial.push_str(&data);
+                    while let Some(end) = partial.find('\n') {
+                        let line = partial[..end].trim_end_matches('\r').to_string();
+                        events.push((time, Event::Output(StdioType::Stdout, line)));
+                        partial.drain(..=end);
+                    }
+                }
+                "x" => {
+                    let (signal, core_dumped) = match data.strip_suffix(" (core dumped)") {
+                        Some(signal) => (signal, true),
+                        None => (data.as_str(), false),
+                    };
+                    let status = match (data.parse(), Signal::from_str(signal)) {
+                        (Ok(code), _) => XStatus::Exited(code),
+                        (_, Ok(signal)) => XStatus::Signaled {
+                            signal,
+                            core_dumped,
+                        },
+                        _ => bail!(
+                            "Line {} of the cast has an invalid exit code '{}'",
+                            i + 1,
+                            data
+                        ),
+                    };
+                    events.push((time, Event::Exit(status)));
+                }
+                _ => {}
+            }
+        }
+        if !partial.is_empty() {
+            events.push((last, Event::Output(StdioType::Stdout, partial)));
+        }
+        Self::new(events, timing)
+    }
+
+    /// Play back a log with a JSON object per line, each with the seconds since the start as
+    /// 'time' and one of:
+    /// - a line of output, as 'line' and the 'stdio' it came from: "stdout", "stderr",
+    ///   "terminal" or "fd N"
+    /// - the code the process exited with, as 'exit'
+    /// - the signal that terminated it, as 'signal', e.g. "SIGTERM", and optionally
+    ///   'core_dumped'
+    ///
+    /// e.g. '{"time": 0.25, "stdio": "stderr", "line": "starting"}' and '{"time": 1.5, "exit":
+    /// 0}'. Without an exit or signal, the status is success
+    pub fn from_jsonl(text: &str, timing: ReplayTiming) -> Result<Self> {
+        let mut events = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let log: LogLine = serde_json::from_str(line)
+                .wrap_err_with(|| format!("Unable to parse line {} of the log", i + 1))?;
+            let event = match log {
+                LogLine {
+                    stdio: Some(stdio),
+                    line: Some(line),
+                    exit: None,
+                    signal: None,
+                    ..
+                } => {
+                    let Some(stdio) = StdioType::from_tag(&stdio) else {
+                        bail!("Line {} of the log has an unknown stdio '{}'", i + 1, stdio);
+                    };
+                    Event::Output(stdio, line)
+                }
+                LogLine {
+                    stdio: None,
+                    line: None,
+                    exit: Some(code),
+                    signal: None,
+                    ..
+                } => Event::Exit(XStatus::Exited(code)),
+                LogLine {
+                    stdio: None,
+                    line: None,
+                    exit: None,
+                    signal: Some(signal),
+                    core_dumped,
+                    ..
+                } => {
+                    let Ok(signal) = Signal::from_str(&signal) else {
+                        bail!(
+                            "Line {} of the log has an unknown signal '{}'",
+                            i + 1,
+                            signal
+                        );
+                    };
+                    Event::Exit(XStatus::Signaled {
+                        signal,
+                        core_dumped,
+                    })
+                }
+                _ => bail!(
+                    "Line {} of the log needs either 'stdio' and 'line', 'exit' or 'signal'",
+                    i + 1
+                ),
+            };
+            events.push((log.time, event));
+        }
+        Self::new(events, timing)
+    }
+
+    fn new(events: Vec<(f64, Event)>, timing: ReplayTiming) -> Result<Self> {
+        if let ReplayTiming::Scaled(factor) = timing {
+            if !factor.is_finite() || factor < 0.0 {
+                bail!("Replay timing can't be scaled by {}", factor);
+            }
+        }
+        if let Some((time, _)) = events
+            .iter()
+            .find(|(time, _)| !time.is_finite() || *time < 0.0)
+        {
+            bail!("Replay events can't happen at {} seconds", time);
+        }
+        let (status_tx, status_rx) = watch::channel(XStatus::Running);
+        Ok(Self {
+            events: Some(events),
+            timing,
+            status_tx: Some(Arc::new(status_tx)),
+            status_rx,
+        })
+    }
+
+    /// Get a streamer for the recorded output. Like 'XChildHandle::streamer()', it can only be
+    /// created once
+    pub fn streamer(&mut self) -> Result<ReplayStreamer> {
+        let (Some(events), Some(status_tx)) = (self.events.take(), self.status_tx.take()) else {
+            bail!("The replay is already being streamed");
+        };
+        let status = events
+            .iter()
+            .rev()
+            .find_map(|(_, event)| match event {
+                Event::Exit(status) => Some(*status),
+                Event::Output(..) => None,
+            })
+            .unwrap_or(XStatus::Exited(0));
+        Ok(ReplayStreamer {
+            events,
+            timing: self.timing,
+            status,
+            status_tx,
+        })
+    }
+
+    /// Wait for the replay to finish, returning the recorded status. 'streamer()' must have
+    /// been called first
+    pub async fn status(&mut self) -> Result<XStatus> {
+        if self.status_tx.is_some() {
+            bail!("Nothing is playing back the replay. Call 'streamer()' first");
+        }
+        match self.status_rx.wait_for(XStatus::finished).await {
+            Ok(status) => Ok(*status),
+            Err(_) => bail!("Stopped playing back the replay before it finished"),
+        }
+    }
+
+    /// Running until the replay has finished, then the recorded status
+    pub fn current_status(&self) -> XStatus {
+        *self.status_rx.borrow()
+    }
+
+    /// See 'XChildHandle::status_changes()'
+    pub fn status_changes(&self) -> impl Stream<Item = XStatus> {
+        WatchStream::new(self.status_rx.clone())
+    }
+}
+
+/// Streams the output of a ReplayChild
+#[derive(Debug)]
+pub struct ReplayStreamer {
+    events: Vec<(f64, Event)>,
+    timing: ReplayTiming,
+    /// Published when the output has all been streamed, or the streamer is dropped
+    status: XStatus,
+    status_tx: Arc<watch::Sender<XStatus>>,
+}
+
+impl ReplayStreamer {
+    /// The recorded lines of output, as 'XStreamer::stream()' yields them
+    pub fn stream(&mut self) -> impl Stream<Item = Result<(StdioType, String)>> + '_ {
+        Box::pin(stream! {
+            let start = tokio::time::Instant::now();
+            for (time, event) in &self.events {
+                if let Some(delay) = self.timing.delay(*time) {
+                    tokio::time::sleep_until(start + delay).await;
+                }
+                if let Event::Output(stdio, line) = event {
+                    yield Ok((*stdio, line.clone()));
+                }
+            }
+            self.status_tx.send_replace(self.status);
+        })
+    }
+}
+
+impl Drop for ReplayStreamer {
+    fn drop(&mut self) {
+        // So that 'ReplayChild::status()' doesn't wait forever on a replay that was cut short
+        if !self.status_tx.borrow().finished() {
+            self.status_tx.send_replace(self.status);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use eyre::bail;
+use eyre::Result;
+use nix::errno::Errno;
+
+/// I/O scheduling class and priority, as used by ioprio_set(2).
+/// Levels range from 0 (highest) to 7 (lowest)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    RealTime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoPriority {
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self {
+            Self::RealTime(level) | Self::BestEffort(level) if *level > 7 => {
+                bail!("I/O priority level must be between 0 and 7, got {}", level)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Encode as the 'ioprio' argument of ioprio_set(2)
+    fn as_raw(&self) -> libc::c_int {
+        const CLASS_SHIFT: libc::c_int = 13;
+        let (class, level) = match self {
+            Self::RealTime(level) => (1, *level),
+            Self::BestEffort(level) => (2, *level),
+            Self::Idle => (3, 0),
+        };
+        (class << CLASS_SHIFT) | level as libc::c_int
+    }
+}
+
+/// Set the I/O priority of the calling process
+pub(crate) fn set_io_priority(priority: IoPriority) -> nix::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            priority.as_raw(),
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// Set the niceness of the calling process
+pub(crate) fn set_nice(nice: i32) -> nix::Result<()> {
+    let res = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    Errno::result(res).map(drop)
+}
+
+/// Write a pre-formatted value to /proc/self/oom_score_adj
+pub(crate) fn set_oom_score_adj(value: &[u8]) -> nix::Result<()> {
+    let fd = unsafe {
+        libc::open(
+            c"/proc/self/oom_score_adj".as_ptr(),
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        )
+    };
+    let fd = Errno::result(fd)?;
+    let res = unsafe { libc::write(fd, value.as_ptr() as *const libc::c_void, value.len()) };
+    let res = Errno::result(res);
+    unsafe { libc::close(fd) };
+    res.map(drop)
+}
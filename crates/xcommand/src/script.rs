@@ -0,0 +1,445 @@
+use crate::command::{Sink, StageIo};
+use crate::env_var::EnvVar;
+use crate::parse::{self, quote};
+use crate::StdioType;
+use crate::XCommand;
+use crate::XStatus;
+use eyre::Result;
+use futures::future::BoxFuture;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::Pid;
+use std::env;
+use std::fmt;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::prelude::RawFd;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// A parsed script: commands joined by '&&', '||', ';' and newlines, grouped with '( ... )'
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptNode {
+    /// A command and its args, with the redirections for it. The command is looked up in PATH
+    /// when it runs
+    Command {
+        words: Vec<String>,
+        redirects: Vec<Redirect>,
+    },
+    /// '( ... )'. The redirections apply to every command within
+    Subshell {
+        body: Box<ScriptNode>,
+        redirects: Vec<Redirect>,
+    },
+    /// 'a && b': b runs if a succeeded
+    And(Box<ScriptNode>, Box<ScriptNode>),
+    /// 'a || b': b runs if a failed
+    Or(Box<ScriptNode>, Box<ScriptNode>),
+    /// 'a ; b', or commands on separate lines
+    Sequence(Vec<ScriptNode>),
+}
+
+impl ScriptNode {
+    /// The number of commands within, whether they will run or not
+    pub fn command_count(&self) -> usize {
+        match self {
+            Self::Command { .. } => 1,
+            Self::Subshell { body, .. } => body.command_count(),
+            Self::And(first, second) | Self::Or(first, second) => {
+                first.command_count() + second.command_count()
+            }
+            Self::Sequence(nodes) => nodes.iter().map(Self::command_count).sum(),
+        }
+    }
+
+    /// Write as an operand of '&&' or '||', grouping what would otherwise bind differently
+    fn fmt_operand(&self, f: &mut fmt::Formatter, right: bool) -> fmt::Result {
+        match self {
+            Self::Sequence(_) => write!(f, "( {} )", self),
+            Self::And(..) | Self::Or(..) if right => write!(f, "( {} )", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+/// The script as it would be parsed back, with words quoted
+impl fmt::Display for ScriptNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Command { words, redirects } => {
+                let words: Vec<_> = words.iter().map(|word| quote(word)).collect();
+                f.write_str(&words.join(" "))?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Self::Subshell { body, redirects } => {
+                write!(f, "( {} )", body)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Self::And(first, second) | Self::Or(first, second) => {
+                first.fmt_operand(f, false)?;
+                f.write_str(match self {
+                    Self::And(..) => " && ",
+                    _ => " || ",
+                })?;
+                second.fmt_operand(f, true)
+            }
+            Self::Sequence(nodes) => {
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("; ")?;
+                    }
+                    write!(f, "{}", node)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Where a redirection sends stdin, stdout or stderr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    /// '> path', '2> path', or with '>>' to append. 'fd' is 1 or 2, anything else fails the
+    /// command it applies to
+    Write {
+        fd: RawFd,
+        path: String,
+        append: bool,
+    },
+    /// '< path'
+    Read { path: String },
+    /// '2>&1' or '1>&2': 'fd' goes wherever 'target' goes at this point. Both are 1 or 2
+    Duplicate { fd: RawFd, target: RawFd },
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Write { fd, path, append } => {
+                let op = if *append { ">>" } else { ">" };
+                match fd {
+                    1 => write!(f, "{} {}", op, quote(path)),
+                    fd => write!(f, "{}{} {}", fd, op, quote(path)),
+                }
+            }
+            Self::Read { path } => write!(f, "< {}", quote(path)),
+            Self::Duplicate { fd, target } => write!(f, "{}>&{}", fd, target),
+        }
+    }
+}
+
+/// Something that happened while running a script. 'index' identifies a command by its
+/// position in the script, counting from 0, whether or not the commands before it ran
+#[derive(Debug)]
+pub enum XScriptEvent {
+    Started {
+        index: usize,
+        pid: Pid,
+        /// As a shell command line, see 'XCommand::to_shell_string()'
+        command: String,
+    },
+    Output {
+        index: usize,
+        stdio: StdioType,
+        line: String,
+    },
+    /// A command couldn't be run: it wasn't found, spawning it failed or one of its
+    /// redirections couldn't be opened. It is followed by a 'Finished' with the status a shell
+    /// would give, 127 or 1 for redirections. For a subshell's redirections, 'index' is that of
+    /// its first command, and none of its commands run
+    Failed {
+        index: usize,
+        error: eyre::Report,
+    },
+    Finished {
+        index: usize,
+        status: XStatus,
+    },
+    /// The script is done, with the status of the last command that finished
+    Done(XStatus),
+}
+
+/// Runs scripts of a small command language without a shell, spawning each command through
+/// XCommand so that its output is streamed from ptys. The language has '&&', '||', ';',
+/// grouping with '( ... )' and the redirections '>', '>>', '<', '2>' and '2>&1'. Words are
+/// quoted as for a POSIX shell. There are no pipes, builtins, globs or variable assignments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XScript {
+    pub root: ScriptNode,
+}
+
+impl XScript {
+    pub fn new(root: ScriptNode) -> Self {
+        Self { root }
+    }
+
+    /// Parse a script, expanding '$VAR' and '${VAR:-default}' against the parent's environment.
+    /// Syntax errors are a 'ParseError'
+    pub fn parse(script: &str) -> Result<Self> {
+        let mut env = Vec::new();
+        for (key, value) in env::vars() {
+            env.push(EnvVar::from_str_pair(&key, &value)?);
+        }
+        Ok(Self::new(parse::script(script, Some(&env))?))
+    }
+
+    /// Like 'parse()', but with '$' taken literally
+    pub fn parse_literal(script: &str) -> Result<Self> {
+        Ok(Self::new(parse::script(script, None)?))
+    }
+
+    /// Run the script on a task of its own. Each command inherits the parent's environment and
+    /// stdin. Dropping the stream stops the script and kills the command that is running
+    pub fn run(&self) -> impl Stream<Item = XScriptEvent> {
+        let (tx, rx) = mpsc::channel(64);
+        let root = self.root.clone();
+        tokio::spawn(async move {
+            let runner = Runner { tx };
+            if let Ok(status) = runner.run(&root, 0, &Redirections::default()).await {
+                let _ = runner.tx.send(XScriptEvent::Done(status)).await;
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+impl fmt::Display for XScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.root)
+    }
+}
+
+/// Where stdin, stdout and stderr go, as redirections have left them. Files are opened once, so
+/// that the commands of a redirected subshell share them
+#[derive(Clone)]
+enum Target {
+    Pty(StdioType),
+    File(Arc<OwnedFd>),
+}
+
+#[derive(Clone)]
+struct Redirections {
+    /// None for the parent's stdin
+    stdin: Option<Arc<OwnedFd>>,
+    stdout: Target,
+    stderr: Target,
+}
+
+impl Default for Redirections {
+    fn default() -> Self {
+        Self {
+            stdin: None,
+            stdout: Target::Pty(StdioType::Stdout),
+            stderr: Target::Pty(StdioType::Stderr),
+        }
+    }
+}
+
+impl Redirections {
+    /// Apply redirections in order, as a shell does
+    fn apply(&self, redirects: &[Redirect]) -> Result<Self> {
+        let mut applied = self.clone();
+        for redirect in redirects {
+            match redirect {
+                Redirect::Write { fd, path, append } => {
+                    let mode = if *append {
+                        OFlag::O_APPEND
+                    } else {
+                        OFlag::O_TRUNC
+                    };
+                    let file = open_file(path, OFlag::O_WRONLY | OFlag::O_CREAT | mode)?;
+                    *applied.output(*fd)? = Target::File(file);
+                }
+                Redirect::Read { path } => {
+                    applied.stdin = Some(open_file(path, OFlag::O_RDONLY)?);
+                }
+                Redirect::Duplicate { fd, target } => {
+                    let target = applied.output(*target)?.clone();
+                    *applied.output(*fd)? = target;
+                }
+            }
+        }
+        Ok(applied)
+    }
+
+    /// The parser only allows 1 and 2, but a Redirect can be built with any fd
+    fn output(&mut self, fd: RawFd) -> Result<&mut Target> {
+        match fd {
+            1 => Ok(&mut self.stdout),
+            2 => Ok(&mut self.stderr),
+            fd => eyre::bail!("Unable to redirect fd {}, only 1 and 2 can be", fd),
+        }
+    }
+
+    /// Duplicate the files for a command to own
+    fn stage_io(&self) -> Result<StageIo> {
+        let sink = |target: &Target| -> Result<Sink> {
+            Ok(match target {
+                Target::Pty(stdio) => Sink::Pty(*stdio),
+                Target::File(file) => Sink::Fd(file.try_clone()?),
+            })
+        };
+        Ok(StageIo {
+            stdin: self
+                .stdin
+                .as_ref()
+                .map(|file| file.try_clone())
+                .transpose()?,
+            stdout: sink(&self.stdout)?,
+            stderr: sink(&self.stderr)?,
+        })
+    }
+}
+
+fn open_file(path: &str, flags: OFlag) -> Result<Arc<OwnedFd>> {
+    let mode = Mode::from_bits_truncate(0o666);
+    match open(path, flags | OFlag::O_CLOEXEC, mode) {
+        Ok(fd) => Ok(Arc::new(unsafe { OwnedFd::from_raw_fd(fd) })),
+        Err(errno) => eyre::bail!("Unable to open '{}': {}", path, errno.desc()),
+    }
+}
+
+/// The consumer dropped the stream of events
+struct Stopped;
+
+struct Runner {
+    tx: mpsc::Sender<XScriptEvent>,
+}
+
+impl Runner {
+    async fn send(&self, event: XScriptEvent) -> Result<(), Stopped> {
+        self.tx.send(event).await.map_err(|_| Stopped)
+    }
+
+    /// Run a node whose first command is 'index'
+    fn run<'a>(
+        &'a self,
+        node: &'a ScriptNode,
+        index: usize,
+        redirections: &'a Redirections,
+    ) -> BoxFuture<'a, Result<XStatus, Stopped>> {
+        Box::pin(async move {
+            match node {
+                ScriptNode::Command { words, redirects } => {
+                    let redirections = match redirections.apply(redirects) {
+                        Ok(redirections) => redirections,
+                        Err(error) => return self.failed(index, error, 1).await,
+                    };
+                    self.run_command(index, words, &redirections).await
+                }
+                ScriptNode::Subshell { body, redirects } => match redirections.apply(redirects) {
+                    Ok(redirections) => self.run(body, index, &redirections).await,
+                    Err(error) => self.failed(index, error, 1).await,
+                },
+                ScriptNode::And(first, second) | ScriptNode::Or(first, second) => {
+                    let status = self.run(first, index, redirections).await?;
+                    let and = matches!(node, ScriptNode::And(..));
+                    if status.success() != and {
+                        return Ok(status);
+                    }
+                    let index = index + first.command_count();
+                    self.run(second, index, redirections).await
+                }
+                ScriptNode::Sequence(nodes) => {
+                    let mut status = XStatus::Exited(0);
+                    let mut index = index;
+                    for node in nodes {
+                        status = self.run(node, index, redirections).await?;
+                        index += node.command_count();
+                    }
+                    Ok(status)
+                }
+            }
+        })
+    }
+
+    async fn run_command(
+        &self,
+        index: usize,
+        words: &[String],
+        redirections: &Redirections,
+    ) -> Result<XStatus, Stopped> {
+        let command = match build(words) {
+            Ok(command) => command,
+            Err(error) => return self.failed(index, error, 127).await,
+        };
+        let spawned = redirections
+            .stage_io()
+            .and_then(|io| command.spawn_with(io));
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(error) => return self.failed(index, error, 127).await,
+        };
+        let pid = child.pid();
+        let command = command.to_string();
+        self.send(XScriptEvent::Started {
+            index,
+            pid,
+            command,
+        })
+        .await?;
+
+        let mut streamer = match child.streamer() {
+            Ok(streamer) => streamer,
+            Err(error) => return self.failed(index, error, 127).await,
+        };
+        let mut stream = streamer.stream();
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok((stdio, line)) => {
+                    let event = XScriptEvent::Output { index, stdio, line };
+                    self.send(event).await?;
+                }
+                Err(error) => self.send(XScriptEvent::Failed { index, error }).await?,
+            }
+        }
+        drop(stream);
+
+        let status = match child.status().await {
+            Ok(status) => status,
+            Err(error) => return self.failed(index, error, 127).await,
+        };
+        self.send(XScriptEvent::Finished { index, status }).await?;
+        Ok(status)
+    }
+
+    /// Report a command that couldn't be run, giving it the status 'code' as a shell would
+    async fn failed(
+        &self,
+        index: usize,
+        error: eyre::Report,
+        code: i32,
+    ) -> Result<XStatus, Stopped> {
+        let status = XStatus::Exited(code);
+        self.send(XScriptEvent::Failed { index, error }).await?;
+        self.send(XScriptEvent::Finished { index, status }).await?;
+        Ok(status)
+    }
+}
+
+/// The XCommand for a command's words, killed if the script is stopped while it runs
+fn build(words: &[String]) -> Result<XCommand> {
+    let Some((name, args)) = words.split_first() else {
+        eyre::bail!("A command needs at least one word");
+    };
+    let path = if name.contains('/') {
+        name.into()
+    } else {
+        match which::which(name) {
+            Ok(path) => path,
+            Err(_) => eyre::bail!("{}: command not found", name),
+        }
+    };
+    let mut builder = XCommand::builder(path)?.kill_on_drop(true);
+    for arg in args {
+        builder = builder.arg(arg)?;
+    }
+    Ok(builder.build())
+}
@@ -0,0 +1,58 @@
+use std::borrow::Cow;
+use std::fmt;
+
+/// What secrets are replaced with
+pub(crate) const MASK: &str = "***";
+
+/// Values passed with 'secret_var()' and 'secret_arg()', which are masked wherever the command
+/// is shown
+#[derive(Clone, Default)]
+pub(crate) struct Secrets(Vec<String>);
+
+impl Secrets {
+    pub fn add(&mut self, value: &str) {
+        if value.is_empty() || self.0.iter().any(|secret| secret == value) {
+            return;
+        }
+        self.0.push(value.to_string());
+        // Mask a secret before any shorter one within it, which would leave the rest showing
+        self.0.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Replace every occurrence of a secret in 'text'
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in &self.0 {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), MASK));
+            }
+        }
+        text
+    }
+
+    /// Where the end of 'text' starts that could be the start of a secret, so that text read
+    /// in chunks can be held back until a secret split across them can be masked whole
+    pub fn unfinished(&self, text: &str) -> usize {
+        self.0
+            .iter()
+            .flat_map(|secret| {
+                (1..secret.len())
+                    .filter(|&len| secret.is_char_boundary(len))
+                    .filter(|&len| text.ends_with(&secret[..len]))
+                    .map(|len| text.len() - len)
+            })
+            .min()
+            .unwrap_or(text.len())
+    }
+}
+
+/// Only the number of secrets, never their values
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secrets({})", self.0.len())
+    }
+}
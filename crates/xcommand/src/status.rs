@@ -0,0 +1,103 @@
+use nix::sys::resource::Resource;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use std::convert::From;
+
+/// State of a child process, as last reported by waitpid()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XStatus {
+    /// The process exited normally with the given code
+    Exited(i32),
+    /// The process was terminated by a signal
+    Signaled { signal: Signal, core_dumped: bool },
+    /// The process was stopped by a signal (job control or ptrace)
+    Stopped(Signal),
+    /// The process was resumed by SIGCONT
+    Continued,
+    /// Nothing has been reported for the process yet
+    Running,
+}
+
+impl XStatus {
+    /// True if the process exited with code 0
+    pub fn success(&self) -> bool {
+        matches!(self, Self::Exited(0))
+    }
+
+    /// True once the process has terminated, one way or another
+    pub fn finished(&self) -> bool {
+        matches!(self, Self::Exited(_) | Self::Signaled { .. })
+    }
+
+    /// The exit code, if the process exited normally
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            Self::Exited(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// The signal that terminated or stopped the process
+    pub fn signal(&self) -> Option<Signal> {
+        match self {
+            Self::Signaled { signal, .. } | Self::Stopped(signal) => Some(*signal),
+            _ => None,
+        }
+    }
+
+    /// True if the process was terminated by a signal and dumped core
+    pub fn core_dumped(&self) -> bool {
+        matches!(
+            self,
+            Self::Signaled {
+                core_dumped: true,
+                ..
+            }
+        )
+    }
+
+    /// The resource limit that killed the process, if it was killed by the signal the kernel
+    /// sends for exceeding one. Limits that make syscalls fail instead (e.g. RLIMIT_AS or
+    /// RLIMIT_NOFILE) can't be told apart from any other failure of the process
+    pub fn exceeded_limit(&self) -> Option<Resource> {
+        match self {
+            Self::Signaled {
+                signal: Signal::SIGXCPU,
+                ..
+            } => Some(Resource::RLIMIT_CPU),
+            Self::Signaled {
+                signal: Signal::SIGXFSZ,
+                ..
+            } => Some(Resource::RLIMIT_FSIZE),
+            _ => None,
+        }
+    }
+
+    /// The status as a shell would report it in '$?': the exit code, or 128 + the signal number
+    /// for processes that were killed or stopped
+    pub fn shell_code(&self) -> Option<i32> {
+        match self {
+            Self::Exited(code) => Some(*code),
+            Self::Signaled { signal, .. } | Self::Stopped(signal) => Some(128 + *signal as i32),
+            Self::Continued | Self::Running => None,
+        }
+    }
+}
+
+impl From<WaitStatus> for XStatus {
+    fn from(input: WaitStatus) -> Self {
+        match input {
+            WaitStatus::Exited(_, code) => Self::Exited(code),
+            WaitStatus::Signaled(_, signal, core_dumped) => Self::Signaled {
+                signal,
+                core_dumped,
+            },
+            WaitStatus::Stopped(_, signal) | WaitStatus::PtraceEvent(_, signal, _) => {
+                Self::Stopped(signal)
+            }
+            WaitStatus::PtraceSyscall(_) => Self::Stopped(Signal::SIGTRAP),
+            WaitStatus::Continued(_) => Self::Continued,
+            WaitStatus::StillAlive => Self::Running,
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::secret::Secrets;
+use crate::StdioType;
+use eyre::Result;
+use eyre::WrapErr;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// How lines are written to the files given with 'XCommandBuilder::tee_stdout()' and friends
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TeeOptions {
+    /// Start each line with the time it was read, in RFC 3339 format
+    pub timestamps: bool,
+    /// Start each line with the output it came from, e.g. '[stderr]'
+    pub tags: bool,
+    /// Rotate a file before it grows past this many bytes
+    pub max_bytes: Option<u64>,
+    /// How many rotated files to keep, from 'path.1' (the newest) to 'path.N'
+    pub keep: usize,
+    /// Compress rotated files, as 'path.1.gz'
+    pub gzip: bool,
+}
+
+/// Which outputs go to a tee file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TeeTarget {
+    Stdout,
+    Stderr,
+    /// Every output: stdout, stderr, side channels and the terminal
+    Combined,
+}
+
+impl TeeTarget {
+    fn includes(self, stdio: StdioType) -> bool {
+        match self {
+            Self::Stdout => stdio == StdioType::Stdout,
+            Self::Stderr => stdio == StdioType::Stderr,
+            Self::Combined => true,
+        }
+    }
+}
+
+/// The tee files of a child, opened before it is spawned
+#[derive(Debug, Default)]
+pub(crate) struct Tees(Vec<(TeeTarget, Arc<Mutex<TeeFile>>)>);
+
+impl Tees {
+    /// Create each file, which has 'secrets' masked in every line written to it
+    pub fn open(
+        targets: &[(TeeTarget, PathBuf)],
+        options: &TeeOptions,
+        secrets: &Secrets,
+    ) -> Result<Self> {
+        let files = targets
+            .iter()
+            .map(|(target, path)| {
+                let file = TeeFile::create(path, options.clone(), secrets.clone())?;
+                Ok((*target, Arc::new(Mutex::new(file))))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(files))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The files that lines from 'stdio' are written to
+    pub fn files_for(&self, stdio: StdioType) -> Vec<Arc<Mutex<TeeFile>>> {
+        self.0
+            .iter()
+            .filter(|(target, _)| target.includes(stdio))
+            .map(|(_, file)| file.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct TeeFile {
+    path: PathBuf,
+    /// None once writing has failed, after which the rest of the output is left out
+    file: Option<File>,
+    /// Bytes written to the current file
+    written: u64,
+    options: TeeOptions,
+    /// Masked before anything reaches the disk
+    secrets: Secrets,
+}
+
+impl TeeFile {
+    fn create(path: &Path, options: TeeOptions, secrets: Secrets) -> Result<Self> {
+        let file = File::create(path)
+            .wrap_err_with(|| format!("Unable to create tee file '{}'", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Some(file),
+            written: 0,
+            options,
+            secrets,
+        })
+    }
+
+    /// Write a line with any secrets masked, which is left out if the file can't be written
+    pub fn write_line(&mut self, stdio: StdioType, line: &str) {
+        if self.file.is_none() {
+            return;
+        }
+        if let Err(e) = self.try_write_line(stdio, line) {
+            debug!(
+                "Unable to write to tee file '{}', leaving out the rest of the output: {}",
+                self.path.display(),
+                e
+            );
+            self.file = None;
+        }
+    }
+
+    fn try_write_line(&mut self, stdio: StdioType, line: &str) -> io::Result<()> {
+        let mut text = String::with_capacity(line.len() + 40);
+        if self.options.timestamps {
+            let now = humantime::format_rfc3339_millis(SystemTime::now());
+            text.push_str(&format!("{} ", now));
+        }
+        if self.options.tags {
+            text.push_str(&format!("[{}] ", stdio.tag()));
+        }
+        text.push_str(&self.secrets.redact(line));
+        text.push('\n');
+
+        if let Some(max_bytes) = self.options.max_bytes {
+            if self.written > 0 && self.written + text.len() as u64 > max_bytes {
+                self.rotate()?;
+            }
+        }
+        if let Some(file) = &mut self.file {
+            // Written a line at a time, so that nothing is lost if we go down
+            file.write_all(text.as_bytes())?;
+            self.written += text.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Shift 'path.N' to 'path.N+1', dropping the oldest, and move the current file to 'path.1'
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let suffix = if self.options.gzip { ".gz" } else { "" };
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}{}", self.path.display(), n, suffix));
+
+        if self.options.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&rotated(self.options.keep))?;
+            for n in (1..self.options.keep).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            if self.options.gzip {
+                gzip(&self.path, &rotated(1))?;
+                fs::remove_file(&self.path)?;
+            } else {
+                fs::rename(&self.path, rotated(1))?;
+            }
+        }
+
+        self.file = Some(File::create(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn gzip(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
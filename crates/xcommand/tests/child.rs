@@ -0,0 +1,40 @@
+use tokio_stream::StreamExt;
+use xcommand::{ReplayChild, ReplayTiming, XChild, XChildStreamer, XCommand, XStatus};
+
+/// The lines and status of 'child', whichever kind it is
+async fn run<C: XChild>(mut child: C) -> (Vec<String>, XStatus) {
+    let mut streamer = child.streamer().unwrap();
+    let lines = streamer
+        .stream()
+        .map(|line| line.unwrap().1)
+        .collect()
+        .await;
+    drop(streamer);
+    let status = child.status().await.unwrap();
+    assert_eq!(child.current_status(), status);
+    (lines, status)
+}
+
+#[tokio::test]
+async fn replays_and_processes_are_interchangeable() {
+    let command = XCommand::builder("/bin/sh")
+        .unwrap()
+        .args(&["-c", "echo one; echo two; exit 4"])
+        .unwrap()
+        .build();
+    let live = run(command.spawn().unwrap()).await;
+
+    let log = r#"{"time": 0.0, "stdio": "stdout", "line": "one"}
+{"time": 0.1, "stdio": "stdout", "line": "two"}
+{"time": 0.2, "exit": 4}"#;
+    let replayed = run(ReplayChild::from_jsonl(log, ReplayTiming::Instant).unwrap()).await;
+
+    assert_eq!(
+        live,
+        (
+            vec!["one".to_string(), "two".to_string()],
+            XStatus::Exited(4)
+        )
+    );
+    assert_eq!(replayed, live);
+}
@@ -0,0 +1,26 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// Children of ours that have exited but not been reaped
+pub fn zombies() -> Vec<i32> {
+    let ours = std::process::id().to_string();
+    fs::read_dir("/proc")
+        .unwrap()
+        .filter_map(|entry| {
+            let pid: i32 = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // The fields after the command name, which is in parentheses and may contain spaces
+            let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+            (fields[0] == "Z" && fields[1] == ours).then_some(pid)
+        })
+        .collect()
+}
+
+/// Wait for children that are reaped in the background, failing if any are left after 'timeout'
+pub async fn assert_no_zombies(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while !zombies().is_empty() {
+        assert!(Instant::now() < deadline, "zombies left: {:?}", zombies());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
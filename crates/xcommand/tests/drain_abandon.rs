@@ -0,0 +1,55 @@
+//! The only test in this binary, as it counts the fds of the whole process
+
+use std::fs;
+use std::time::{Duration, Instant};
+use xcommand::{DrainPolicy, XCommand};
+
+const SPAWNS: usize = 20;
+
+fn open_fds() -> usize {
+    fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+/// Threads reading into a drain buffer
+fn drain_threads() -> usize {
+    fs::read_dir("/proc/self/task")
+        .unwrap()
+        .filter_map(|task| fs::read_to_string(task.ok()?.path().join("comm")).ok())
+        .filter(|name| name.trim_end() == "xcommand-drain")
+        .count()
+}
+
+#[tokio::test]
+async fn blocked_drains_finish_once_the_child_is_dropped() {
+    let command = XCommand::builder("/usr/bin/yes")
+        .unwrap()
+        .drain_output(DrainPolicy::Block, 1024)
+        .kill_on_drop(true)
+        .build();
+    let baseline = (open_fds(), drain_threads());
+
+    for i in 0..SPAWNS {
+        let mut child = command.spawn().unwrap();
+        // Long enough for the buffer to fill, leaving the drain waiting for room
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        if i % 2 == 0 {
+            let streamer = child.streamer().unwrap();
+            drop(child);
+            drop(streamer);
+        } else {
+            drop(child);
+        }
+    }
+
+    // The drain threads read on to the end of the output in the background
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while (open_fds(), drain_threads()) != baseline {
+        assert!(
+            Instant::now() < deadline,
+            "fds and threads {:?}, from {:?}",
+            (open_fds(), drain_threads()),
+            baseline
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
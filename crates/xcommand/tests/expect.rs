@@ -0,0 +1,45 @@
+use std::time::Duration;
+use xcommand::{XCommand, XStatus};
+
+#[tokio::test]
+async fn unmatched_output_is_masked_in_errors() {
+    let command = XCommand::builder("/bin/sh")
+        .unwrap()
+        .args(&["-c", "echo \"token $TOKEN\""])
+        .unwrap()
+        .secret_var("TOKEN", "hunter2")
+        .unwrap()
+        .build();
+    let mut child = command.spawn().unwrap();
+    let mut expect = child.expect().unwrap();
+
+    let error = expect
+        .expect("never", Duration::from_secs(5))
+        .await
+        .unwrap_err();
+    let error = format!("{:?}", error);
+    assert!(error.contains("token ***"), "{}", error);
+    assert!(!error.contains("hunter2"), "{}", error);
+    let debug = format!("{:?}", expect);
+    assert!(debug.contains("token ***"), "{}", debug);
+    assert!(!debug.contains("hunter2"), "{}", debug);
+}
+
+#[tokio::test]
+async fn output_is_only_read_as_fast_as_it_is_expected() {
+    let command = XCommand::builder("/usr/bin/head")
+        .unwrap()
+        .args(&["-c", "10000000", "/dev/zero"])
+        .unwrap()
+        .build();
+    let mut child = command.spawn().unwrap();
+    let mut expect = child.expect().unwrap();
+
+    // Far more than is queued, so the child is left waiting to write the rest
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(child.current_status(), XStatus::Running);
+
+    let rest = expect.expect_eof(Duration::from_secs(30)).await.unwrap();
+    assert_eq!(rest.len(), 64 * 1024);
+    assert!(child.status().await.unwrap().success());
+}
@@ -0,0 +1,54 @@
+//! The only test in this binary, as it counts the fds and zombies of the whole process
+
+mod common;
+
+use std::fs;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use xcommand::XCommand;
+
+const SPAWNS: usize = 1500;
+
+fn open_fds() -> usize {
+    fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+#[tokio::test]
+async fn pty_masters_are_closed_and_children_reaped_after_thousands_of_spawns() {
+    let command = XCommand::builder("/bin/echo")
+        .unwrap()
+        .args(&["hello"])
+        .unwrap()
+        .kill_on_drop(true)
+        .build();
+    // Warm up the runtime and the blocking pool, which keep fds of their own
+    let mut child = command.spawn().unwrap();
+    let mut streamer = child.streamer().unwrap();
+    let _: Vec<_> = streamer.stream().collect().await;
+    drop(streamer);
+    child.status().await.unwrap();
+    let baseline = open_fds();
+
+    for _ in 0..SPAWNS {
+        // Streamed to the end
+        let mut child = command.spawn().unwrap();
+        let mut streamer = child.streamer().unwrap();
+        let lines: Vec<_> = streamer.stream().collect().await;
+        assert_eq!(lines.len(), 1);
+        drop(streamer);
+        child.status().await.unwrap();
+
+        // Dropped without a streamer
+        drop(command.spawn().unwrap());
+
+        // Dropped with a streamer that was never polled
+        let mut child = command.spawn().unwrap();
+        let streamer = child.streamer().unwrap();
+        drop(child);
+        drop(streamer);
+    }
+
+    assert_eq!(open_fds(), baseline);
+    // Every child is reaped, however far it got
+    common::assert_no_zombies(Duration::from_secs(10)).await;
+}
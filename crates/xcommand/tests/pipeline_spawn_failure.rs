@@ -0,0 +1,26 @@
+//! The only test in this binary, as it looks at every child of the process
+
+mod common;
+
+use std::time::Duration;
+use xcommand::{XCommand, XPipeline};
+
+#[tokio::test]
+async fn stages_before_a_failed_one_are_reaped() {
+    let sleep = || {
+        XCommand::builder("/bin/sleep")
+            .unwrap()
+            .args(&["10"])
+            .unwrap()
+            .build()
+    };
+    let missing = XCommand::builder("/nonexistent/command").unwrap().build();
+    let pipeline = XPipeline::new()
+        .stage(sleep())
+        .pty_stage(sleep())
+        .stage(missing);
+    assert!(pipeline.spawn().is_err());
+
+    // The stages are reaped in the background
+    common::assert_no_zombies(Duration::from_secs(5)).await;
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use xcommand::{SpawnBackend, XCommand};
+
+#[tokio::test]
+async fn output_is_seen_while_expecting() {
+    let command = XCommand::builder("/bin/sh")
+        .unwrap()
+        .args(&[
+            "-c",
+            "echo starting; echo ready; read line; echo \"got $line\"",
+        ])
+        .unwrap()
+        .pty_stdin(true)
+        .backend(SpawnBackend::Fork)
+        .build();
+    let mut child = command.spawn().unwrap();
+    let mut expect = child.expect().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    let line = child.wait_until_output("ready", timeout).await.unwrap();
+    assert_eq!(line, "ready");
+    expect.expect("ready", timeout).await.unwrap();
+    expect.send_line("go").await.unwrap();
+    let line = child.wait_until_output("got", timeout).await.unwrap();
+    assert_eq!(line, "got go");
+
+    // Once the child has finished, the error shows its last lines
+    let error = child.wait_until_output("never", timeout).await.unwrap_err();
+    assert!(format!("{:?}", error).contains("got go"), "{:?}", error);
+}
+
+#[tokio::test]
+async fn output_just_before_exiting_is_seen() {
+    let command = XCommand::builder("/bin/sh")
+        .unwrap()
+        .args(&["-c", "echo ready"])
+        .unwrap()
+        .build();
+    for _ in 0..5 {
+        let mut child = command.spawn().unwrap();
+        let mut streamer = child.streamer().unwrap();
+        // A slow consumer, so that the child has finished before its line is passed on
+        let streaming = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let _: Vec<_> = streamer.stream().collect().await;
+        });
+        let line = child
+            .wait_until_output("ready", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(line, "ready");
+        streaming.await.unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::Path;
+use tokio_stream::StreamExt;
+use xcommand::{ReplayChild, ReplayTiming, XCommand, XStatus};
+
+/// Run 'script' with 'hunter2' as a secret, recording it to 'path', and return its status
+async fn record(script: &str, path: &Path) -> XStatus {
+    let command = XCommand::builder("/bin/sh")
+        .unwrap()
+        .args(&["-c", script])
+        .unwrap()
+        .secret_var("TOKEN", "hunter2")
+        .unwrap()
+        .record(path)
+        .build();
+    let mut child = command.spawn().unwrap();
+    let mut streamer = child.streamer().unwrap();
+    let _: Vec<_> = streamer.stream().collect().await;
+    child.status().await.unwrap()
+}
+
+#[tokio::test]
+async fn recordings_mask_secrets_and_end_with_the_status() {
+    let dir = std::env::temp_dir().join(format!("xcommand-record-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("session.cast");
+    let cases = [
+        // Split across reads, which has to be masked whole
+        (
+            "echo \"$TOKEN\"; printf hun; sleep 0.2; printf 'ter2\\n'; exit 3",
+            XStatus::Exited(3),
+        ),
+        (
+            "kill -TERM $$",
+            XStatus::Signaled {
+                signal: nix::sys::signal::Signal::SIGTERM,
+                core_dumped: false,
+            },
+        ),
+    ];
+    for (script, expected) in cases {
+        assert_eq!(record(script, &path).await, expected);
+        let cast = fs::read_to_string(&path).unwrap();
+        assert!(!cast.contains("hunter2"), "{}", cast);
+        assert!(!cast.contains("hun\""), "{}", cast);
+
+        let mut replay = ReplayChild::from_cast(&cast, ReplayTiming::Instant).unwrap();
+        let mut streamer = replay.streamer().unwrap();
+        let lines: Vec<_> = streamer
+            .stream()
+            .map(|line| line.unwrap().1)
+            .collect()
+            .await;
+        if expected.code().is_some() {
+            assert_eq!(lines, ["***", "***"]);
+        }
+        assert_eq!(replay.status().await.unwrap(), expected, "{}", cast);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,82 @@
+use tokio_stream::StreamExt;
+use xcommand::{Redirect, ScriptNode, XScript, XScriptEvent, XStatus};
+
+/// An event as a short string
+fn short(event: &XScriptEvent) -> String {
+    match event {
+        XScriptEvent::Started { index, .. } => format!("started {}", index),
+        XScriptEvent::Output { index, line, .. } => format!("output {} {}", index, line),
+        XScriptEvent::Failed { index, .. } => format!("failed {}", index),
+        XScriptEvent::Finished { index, status } => format!("finished {} {:?}", index, status),
+        XScriptEvent::Done(status) => format!("done {:?}", status),
+    }
+}
+
+/// The events of running 'script', as short strings
+async fn events(script: &str) -> Vec<String> {
+    let script = XScript::parse_literal(script).unwrap();
+    script.run().map(|event| short(&event)).collect().await
+}
+
+#[tokio::test]
+async fn every_failure_is_followed_by_a_finish() {
+    let missing = "/nonexistent/dir/file";
+    let cases = [
+        format!("/bin/echo hi > {}", missing),
+        format!("( /bin/echo hi; /bin/echo there ) > {}", missing),
+        "/nonexistent/command".to_string(),
+    ];
+    for script in cases {
+        let events = events(&script).await;
+        let code = if script.contains('>') { 1 } else { 127 };
+        let expected = [
+            "failed 0".to_string(),
+            format!("finished 0 {:?}", XStatus::Exited(code)),
+            format!("done {:?}", XStatus::Exited(code)),
+        ];
+        assert_eq!(events, expected, "{}", script);
+    }
+}
+
+/// The events of running a hand-built 'node'
+async fn node_events(node: ScriptNode) -> Vec<String> {
+    XScript::new(node)
+        .run()
+        .map(|event| short(&event))
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn hand_built_nodes_that_cant_run_fail() {
+    let echo = |redirects| ScriptNode::Command {
+        words: vec!["/bin/echo".to_string(), "hi".to_string()],
+        redirects,
+    };
+    let cases = [
+        (
+            ScriptNode::Command {
+                words: vec![],
+                redirects: vec![],
+            },
+            127,
+        ),
+        (
+            echo(vec![Redirect::Write {
+                fd: 3,
+                path: "/dev/null".to_string(),
+                append: false,
+            }]),
+            1,
+        ),
+        (echo(vec![Redirect::Duplicate { fd: 2, target: 5 }]), 1),
+    ];
+    for (node, code) in cases {
+        let expected = [
+            "failed 0".to_string(),
+            format!("finished 0 {:?}", XStatus::Exited(code)),
+            format!("done {:?}", XStatus::Exited(code)),
+        ];
+        assert_eq!(node_events(node.clone()).await, expected, "{:?}", node);
+    }
+}
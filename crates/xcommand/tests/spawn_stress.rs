@@ -0,0 +1,87 @@
+use log::{debug, LevelFilter};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use xcommand::{SpawnBackend, XCommand};
+
+const SPAWNS_PER_TASK: usize = 200;
+const TASKS: usize = 4;
+const ALLOCATING_THREADS: usize = 4;
+
+/// Allocate, free and log as fast as possible until 'stop' is set, so that the allocator's and
+/// logger's locks are likely to be held by another thread whenever we fork
+fn churn(stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut i = 0usize;
+        while !stop.load(Ordering::Relaxed) {
+            let sizes = [16, 256, 4096, 65536, 1 << 20];
+            let buffers: Vec<Vec<u8>> = sizes
+                .iter()
+                .map(|size| vec![i as u8; size + i % 7])
+                .collect();
+            let text = format!("{:?}", &buffers[0]);
+            debug!("churn {} {}", i, text.len());
+            i = i.wrapping_add(1);
+        }
+    })
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn fork_under_heavy_allocation_and_logging_does_not_hang() {
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .target(env_logger::Target::Pipe(Box::new(io::sink())))
+        .init();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let churners: Vec<_> = (0..ALLOCATING_THREADS)
+        .map(|_| churn(stop.clone()))
+        .collect();
+
+    let command = Arc::new(
+        XCommand::builder("/bin/sh")
+            .unwrap()
+            .args(&["-c", "echo \"$GREETING\" \"$@\"", "sh", "a", "b", "c"])
+            .unwrap()
+            .var("GREETING", "hello")
+            .unwrap()
+            .secret_var("TOKEN", "hunter2")
+            .unwrap()
+            .backend(SpawnBackend::Fork)
+            .build(),
+    );
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|_| {
+            let command = command.clone();
+            tokio::spawn(async move {
+                for _ in 0..SPAWNS_PER_TASK {
+                    let mut child = command.spawn().unwrap();
+                    let mut streamer = child.streamer().unwrap();
+                    let lines: Vec<_> = streamer
+                        .stream()
+                        .map(|line| line.unwrap().1)
+                        .collect()
+                        .await;
+                    assert_eq!(lines, ["hello a b c"]);
+                    assert!(child.status().await.unwrap().success());
+                }
+            })
+        })
+        .collect();
+
+    // A child that deadlocked before exec'ing would never exit
+    let all = futures::future::join_all(tasks);
+    let results = tokio::time::timeout(Duration::from_secs(120), all)
+        .await
+        .expect("spawning hung");
+    stop.store(true, Ordering::Relaxed);
+    for result in results {
+        result.unwrap();
+    }
+    for churner in churners {
+        churner.join().unwrap();
+    }
+}
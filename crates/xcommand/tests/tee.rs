@@ -0,0 +1,52 @@
+use std::fs;
+use tokio_stream::StreamExt;
+use xcommand::XCommand;
+
+#[tokio::test]
+async fn secrets_are_masked_in_tee_files() {
+    let dir = std::env::temp_dir().join(format!("xcommand-tee-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("combined.log");
+    let command = XCommand::builder("/bin/sh")
+        .unwrap()
+        .args(&["-c", "echo \"token $TOKEN\"; echo \"arg $1\"", "sh"])
+        .unwrap()
+        .secret_arg("hunter3")
+        .unwrap()
+        .secret_var("TOKEN", "hunter2")
+        .unwrap()
+        .tee_combined(&path)
+        .build();
+    let mut child = command.spawn().unwrap();
+    let mut streamer = child.streamer().unwrap();
+    let _: Vec<_> = streamer.stream().collect().await;
+    child.status().await.unwrap();
+
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(text, "token ***\narg ***\n");
+}
+
+#[tokio::test]
+async fn a_slow_stream_loses_nothing_to_a_tee() {
+    let dir = std::env::temp_dir().join(format!("xcommand-tee-slow-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // About 2 MiB, more than is kept in memory
+    let command = XCommand::builder("/usr/bin/seq")
+        .unwrap()
+        .args(&["1", "300000"])
+        .unwrap()
+        .tee_stdout(dir.join("stdout.log"))
+        .build();
+    let mut child = command.spawn().unwrap();
+    let mut streamer = child.streamer().unwrap();
+    // Nothing is streamed until the child is done
+    child.status().await.unwrap();
+
+    let lines: Vec<_> = streamer.stream().collect().await;
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(lines.len(), 300_000);
+    let stats = child.drain_stats().unwrap();
+    assert_eq!(stats.dropped_lines, 0);
+    assert!(stats.spilled_lines > 0);
+}